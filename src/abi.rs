@@ -26,7 +26,7 @@
  * Jump
 
 * UNITCONTROL:
- * UnitBInd
+ * UnitBind
  * UnitControl
  * UnitRadar
  * UnitLocate
@@ -53,6 +53,22 @@ impl Variable {
         }
     }
 
+    /// 程序计数器`@counter`，对其赋值相当于跳转
+    pub fn counter() -> Self {
+        Self {
+            name: VariableName::named("@counter"),
+            value: VariableValue::MetaAttrib,
+        }
+    }
+
+    /// null，没有返回值的内置函数的值
+    pub fn null() -> Self {
+        Self {
+            name: VariableName::None,
+            value: VariableValue::UnknowType,
+        }
+    }
+
     /// 零，或者说zero/null，不应该作为左值出现
     pub fn zero() -> Self {
         Self {
@@ -62,7 +78,7 @@ impl Variable {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum VariableName {
     Named(String),
     Index(usize),
@@ -88,14 +104,6 @@ impl VariableName {
         }
     }
 
-    pub fn as_index(&self) -> Option<&usize> {
-        if let Self::Index(v) = self {
-            Some(v)
-        } else {
-            None
-        }
-    }
-
     /// Returns `true` if the variable name is [`Named`].
     ///
     /// [`Named`]: VariableName::Named
@@ -111,23 +119,18 @@ impl VariableName {
     pub fn is_index(&self) -> bool {
         matches!(self, Self::Index(..))
     }
-
-    /// Returns `true` if the variable name is [`None`].
-    ///
-    /// [`None`]: VariableName::None
-    #[must_use]
-    pub fn is_none(&self) -> bool {
-        matches!(self, Self::None)
-    }
 }
 
 #[derive(Debug, Clone)]
 pub enum VariableValue {
     Number(f64),
+    /// 颜色常量，和游戏中的`packcolor`一样是以rgba8888为位模式的double
+    ///
+    /// 和数字分开记录，只有它会被输出为`%rrggbbaa`
+    Color(u32),
     String(String),
-    // UnknowString,
-    Variable(String),
-    FnReturn(String),
+    /// 函数的返回值，会被下一次调用覆盖
+    FnReturn,
     UnknowType,
     MetaAttrib,
 }
@@ -165,56 +168,18 @@ impl VariableValue {
         }
     }
 
-    pub fn as_string(&self) -> Option<&String> {
-        if let Self::String(v) = self {
-            Some(v)
-        } else {
-            None
-        }
-    }
-
-    /// Returns `true` if the variable value is [`Variable`].
-    ///
-    /// [`Variable`]: VariableValue::Variable
-    #[must_use]
-    pub fn is_variable(&self) -> bool {
-        matches!(self, Self::Variable(..))
-    }
-
-    pub fn as_variable(&self) -> Option<&String> {
-        if let Self::Variable(v) = self {
-            Some(v)
-        } else {
-            None
-        }
-    }
-
     /// Returns `true` if the variable value is [`FnReturn`].
     ///
     /// [`FnReturn`]: VariableValue::FnReturn
     #[must_use]
     pub fn is_fn_return(&self) -> bool {
-        matches!(self, Self::FnReturn(..))
-    }
-
-    pub fn as_fn_return(&self) -> Option<&String> {
-        if let Self::FnReturn(v) = self {
-            Some(v)
-        } else {
-            None
-        }
-    }
-
-    /// Returns `true` if the variable value is [`UnknowType`].
-    ///
-    /// [`UnknowType`]: VariableValue::UnknowType
-    #[must_use]
-    pub fn is_unknow_type(&self) -> bool {
-        matches!(self, Self::UnknowType)
+        matches!(self, Self::FnReturn)
     }
 }
 
 /// 逻辑语句
+///
+/// 除了`Jump`，其余语句都和mlog中的指令一一对应
 #[derive(Debug, Clone)]
 pub enum Statement {
     Set {
@@ -234,20 +199,184 @@ pub enum Statement {
         right: Variable,
         target: usize,
     },
+
+    // IO
+    /// 从内存元中读取
+    Read {
+        result: Variable,
+        cell: Variable,
+        index: Variable,
+    },
+    /// 写入内存元
+    Write {
+        value: Variable,
+        cell: Variable,
+        index: Variable,
+    },
+    /// 参数不足六个的部分输出时会补零
+    Draw {
+        command: DrawCommand,
+        args: Vec<Variable>,
+    },
+    Print {
+        value: Variable,
+    },
+
+    // CONTROL
+    DrawFlush {
+        display: Variable,
+    },
+    PrintFlush {
+        message: Variable,
+    },
+    GetLink {
+        result: Variable,
+        index: Variable,
+    },
+    /// 参数不足四个的部分输出时会补零
+    Control {
+        command: ControlCommand,
+        block: Variable,
+        args: Vec<Variable>,
+    },
+    Radar {
+        filters: [Radar; 3],
+        sort: RadarSort,
+        from: Variable,
+        /// 非零时按照升序
+        order: Variable,
+        result: Variable,
+    },
+    Sensor {
+        result: Variable,
+        target: Variable,
+        /// 一般是`@copper`这样的元属性，也可以是保存了它的变量
+        attrib: Variable,
+    },
+
+    // OPTION
+    Lookup {
+        result: Variable,
+        kind: LookUp,
+        index: Variable,
+    },
+    PackColor {
+        result: Variable,
+        r: Variable,
+        g: Variable,
+        b: Variable,
+        a: Variable,
+    },
+
+    // CONTROLFLOW
+    /// 单位为秒
+    Wait {
+        time: Variable,
+    },
+    /// 暂停处理器
+    Stop,
+    /// 回到第一条语句
+    End,
+
+    // UNITCONTROL
+    UnitBind {
+        unit_type: Variable,
+    },
+    /// 参数不足五个的部分输出时会补零
+    UnitControl {
+        command: UnitControlCommand,
+        args: Vec<Variable>,
+    },
+    UnitRadar {
+        filters: [Radar; 3],
+        sort: RadarSort,
+        order: Variable,
+        result: Variable,
+    },
+    UnitLocate {
+        kind: UnitLocateKind,
+        /// 只在kind为`building`时有效
+        group: BuildingGroup,
+        /// 只在kind为`building`时有效
+        enemy: Variable,
+        /// 只在kind为`ore`时有效
+        ore: Variable,
+        out_x: Variable,
+        out_y: Variable,
+        found: Variable,
+        /// kind为`ore`时无效
+        building: Variable,
+    },
 }
 
 impl Statement {
-    pub fn jump_target(&self) -> Option<usize> {
-        if let Self::Jump { target, .. } = self {
-            Some(*target)
-        } else {
-            None
+    /// 只写入一个变量的指令的结果，`Set`和`Operation`除外
+    pub fn result_mut(&mut self) -> Option<&mut Variable> {
+        match self {
+            Self::Read { result, .. }
+            | Self::GetLink { result, .. }
+            | Self::Radar { result, .. }
+            | Self::Sensor { result, .. }
+            | Self::Lookup { result, .. }
+            | Self::PackColor { result, .. }
+            | Self::UnitRadar { result, .. } => Some(result),
+            _ => None,
         }
     }
 
-    pub fn reset(&mut self, new_target: usize) {
-        if let Self::Jump { target, .. } = self {
-            *target = new_target
+    /// 指令读取的变量
+    pub fn operands_mut(&mut self) -> Vec<&mut Variable> {
+        match self {
+            Self::Set { value, .. } | Self::Print { value } => vec![value],
+            Self::Operation { v1, v2, .. } => vec![v1, v2],
+            Self::Jump { left, right, .. } => vec![left, right],
+            Self::Read { cell, index, .. } => vec![cell, index],
+            Self::Write { value, cell, index } => vec![value, cell, index],
+            Self::Draw { args, .. } => args.iter_mut().collect(),
+            Self::DrawFlush { display } => vec![display],
+            Self::PrintFlush { message } => vec![message],
+            Self::GetLink { index, .. } | Self::Lookup { index, .. } => vec![index],
+            Self::Control { block, args, .. } => {
+                std::iter::once(block).chain(args.iter_mut()).collect()
+            }
+            Self::Radar { from, order, .. } => vec![from, order],
+            Self::Sensor { target, attrib, .. } => vec![target, attrib],
+            Self::PackColor { r, g, b, a, .. } => vec![r, g, b, a],
+            Self::Wait { time } => vec![time],
+            Self::Stop | Self::End => vec![],
+            Self::UnitBind { unit_type } => vec![unit_type],
+            Self::UnitControl { command, args } => {
+                let nr_inputs = args.len().saturating_sub(command.nr_outputs());
+                args.iter_mut().take(nr_inputs).collect()
+            }
+            Self::UnitRadar { order, .. } => vec![order],
+            Self::UnitLocate { enemy, ore, .. } => vec![enemy, ore],
+        }
+    }
+
+    /// 指令写入的变量
+    pub fn results(&self) -> Vec<&Variable> {
+        match self {
+            Self::Set { result, .. } | Self::Operation { result, .. } => vec![result],
+            Self::Read { result, .. }
+            | Self::GetLink { result, .. }
+            | Self::Radar { result, .. }
+            | Self::Sensor { result, .. }
+            | Self::Lookup { result, .. }
+            | Self::PackColor { result, .. }
+            | Self::UnitRadar { result, .. } => vec![result],
+            Self::UnitControl { command, args } => args
+                [args.len().saturating_sub(command.nr_outputs())..]
+                .iter()
+                .collect(),
+            Self::UnitLocate {
+                out_x,
+                out_y,
+                found,
+                building,
+                ..
+            } => vec![out_x, out_y, found, building],
+            _ => vec![],
         }
     }
 
@@ -281,6 +410,71 @@ pub enum JumpCondition {
     Always,
 }
 
+impl JumpCondition {
+    /// 两边都是常量时，跳转是否发生
+    pub fn eval(&self, l: f64, r: f64) -> bool {
+        match self {
+            Self::Eq | Self::Seq => l == r,
+            Self::Neq => l != r,
+            Self::Lr => l < r,
+            Self::LrE => l <= r,
+            Self::Gr => l > r,
+            Self::GrE => l >= r,
+            Self::Always => true,
+        }
+    }
+
+    /// 相反的条件，严格相等和无条件没有相反的条件
+    pub fn negate(&self) -> Option<Self> {
+        let negated = match self {
+            Self::Eq => Self::Neq,
+            Self::Neq => Self::Eq,
+            Self::Lr => Self::GrE,
+            Self::LrE => Self::Gr,
+            Self::Gr => Self::LrE,
+            Self::GrE => Self::Lr,
+            Self::Seq | Self::Always => return None,
+        };
+        Some(negated)
+    }
+}
+
+/// 常量折叠，和`Statement::Operation`一样，一元运算的右边是0
+///
+/// 布尔运算的结果为1或0，位运算先转换为long再计算，移位的位数和java一样只取低6位
+///
+/// 结果不是有限的数时不折叠，交给游戏计算
+pub fn fold(op: Symbol, l: f64, r: f64) -> Option<f64> {
+    use std::ops::*;
+    let number = match op {
+        Symbol::Add => l.add(r),
+        Symbol::Sub => l.sub(r),
+        Symbol::Mul => l.mul(r),
+        Symbol::Div => l.div(r),
+        Symbol::IDiv => l.div_euclid(r),
+        Symbol::Rem => l.rem(r),
+        Symbol::Pow => l.powf(r),
+        Symbol::Eq => (l == r) as u8 as f64,
+        Symbol::Neq => (l != r) as u8 as f64,
+        // `!x` 生成为 `op Not r x 0`
+        Symbol::Not => (l == r) as u8 as f64,
+        Symbol::Lr => (l < r) as u8 as f64,
+        Symbol::Gr => (l > r) as u8 as f64,
+        Symbol::LrE => (l <= r) as u8 as f64,
+        Symbol::GrE => (l >= r) as u8 as f64,
+        // Seq无法保证准确性：因为类型系统不完善
+        Symbol::Seq => (l == r) as u8 as f64,
+        Symbol::Shl => (l as i64).wrapping_shl(r as i64 as u32) as f64,
+        Symbol::Shr => (l as i64).wrapping_shr(r as i64 as u32) as f64,
+        Symbol::Band => ((l as i64) & (r as i64)) as f64,
+        Symbol::Bor => ((l as i64) | (r as i64)) as f64,
+        Symbol::Xor => ((l as i64) ^ (r as i64)) as f64,
+        Symbol::Flip => !(l as i64) as f64,
+        _ => return None,
+    };
+    Some(number).filter(|number| number.is_finite())
+}
+
 impl TryFrom<Symbol> for JumpCondition {
     type Error = ();
 
@@ -306,20 +500,190 @@ impl TryFrom<Symbol> for JumpCondition {
 //     Meta,
 // }
 
-// pub enum LookUp {
-//     UnitCount,
-//     ItemCount,
-//     BlockCount,
-//     LiquidCount,
-// }
+/// 生成子命令之类的枚举和它们在mlog中的名字，作为子命令的还有参数的个数
+macro_rules! sub_commands {
+    () => {};
+    (
+        $(#[$meta:meta])*
+        $enum:ident {
+            $($name:ident, $src:literal;)*
+        }
+        $($rest:tt)*
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $enum {
+            $($name,)*
+        }
 
-// pub enum Radar {
-//     Any,
-//     Enemy,
-//     Ally,
-//     Player,
-//     Attacker,
-//     Flying,
-//     Boss,
-//     Ground,
-// }
+        impl $enum {
+            pub const ALL: &'static [Self] = &[$(Self::$name,)*];
+
+            /// 在mlog中的名字
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Self::$name => $src,)*
+                }
+            }
+
+            pub fn from_name(name: &str) -> Option<Self> {
+                Self::ALL.iter().copied().find(|this| this.name() == name)
+            }
+
+            pub fn names() -> Vec<&'static str> {
+                Self::ALL.iter().map(Self::name).collect()
+            }
+        }
+
+        impl std::fmt::Display for $enum {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}", self.name())
+            }
+        }
+
+        sub_commands! {$($rest)*}
+    };
+    (
+        $(#[$meta:meta])*
+        $enum:ident {
+            $($name:ident, $src:literal, $nr_args:literal;)*
+        }
+        $($rest:tt)*
+    ) => {
+        sub_commands! {
+            $(#[$meta])*
+            $enum {
+                $($name, $src;)*
+            }
+        }
+
+        impl $enum {
+            /// 需要的参数的个数
+            pub fn nr_args(&self) -> usize {
+                match self {
+                    $(Self::$name => $nr_args,)*
+                }
+            }
+        }
+
+        sub_commands! {$($rest)*}
+    };
+}
+
+sub_commands! {
+    /// draw的子命令
+    DrawCommand {
+        Clear    ,"clear"    ,3;
+        Color    ,"color"    ,4;
+        Col      ,"col"      ,1;
+        Stroke   ,"stroke"   ,1;
+        Line     ,"line"     ,4;
+        Rect     ,"rect"     ,4;
+        LineRect ,"lineRect" ,4;
+        Poly     ,"poly"     ,5;
+        LinePoly ,"linePoly" ,5;
+        Triangle ,"triangle" ,6;
+        Image    ,"image"    ,5;
+        Print    ,"print"    ,3;
+    }
+
+    /// control的子命令
+    ControlCommand {
+        Enabled ,"enabled" ,1;
+        Shoot   ,"shoot"   ,3;
+        Shootp  ,"shootp"  ,2;
+        Config  ,"config"  ,1;
+        Color   ,"color"   ,1;
+    }
+
+    /// ucontrol的子命令
+    UnitControlCommand {
+        Idle         ,"idle"         ,0;
+        Stop         ,"stop"         ,0;
+        Move         ,"move"         ,2;
+        Approach     ,"approach"     ,3;
+        Pathfind     ,"pathfind"     ,2;
+        AutoPathfind ,"autoPathfind" ,0;
+        Boost        ,"boost"        ,1;
+        Target       ,"target"       ,3;
+        Targetp      ,"targetp"      ,2;
+        ItemDrop     ,"itemDrop"     ,2;
+        ItemTake     ,"itemTake"     ,3;
+        PayDrop      ,"payDrop"      ,0;
+        PayTake      ,"payTake"      ,1;
+        PayEnter     ,"payEnter"     ,0;
+        Mine         ,"mine"         ,2;
+        Flag         ,"flag"         ,1;
+        Build        ,"build"        ,5;
+        GetBlock     ,"getBlock"     ,5;
+        Within       ,"within"       ,4;
+        Unbind       ,"unbind"       ,0;
+    }
+
+    /// lookup查询的内容
+    LookUp {
+        Block  ,"block";
+        Unit   ,"unit";
+        Item   ,"item";
+        Liquid ,"liquid";
+    }
+
+    /// radar和uradar的筛选条件
+    Radar {
+        Any      ,"any";
+        Enemy    ,"enemy";
+        Ally     ,"ally";
+        Player   ,"player";
+        Attacker ,"attacker";
+        Flying   ,"flying";
+        Boss     ,"boss";
+        Ground   ,"ground";
+    }
+
+    /// radar和uradar的排序方式
+    RadarSort {
+        Distance  ,"distance";
+        Health    ,"health";
+        Shield    ,"shield";
+        Armor     ,"armor";
+        MaxHealth ,"maxHealth";
+    }
+
+    /// ulocate查找的内容
+    UnitLocateKind {
+        Ore      ,"ore";
+        Building ,"building";
+        Spawn    ,"spawn";
+        Damaged  ,"damaged";
+    }
+
+    /// ulocate查找的建筑的类别
+    BuildingGroup {
+        Core      ,"core";
+        Storage   ,"storage";
+        Generator ,"generator";
+        Turret    ,"turret";
+        Factory   ,"factory";
+        Repair    ,"repair";
+        Battery   ,"battery";
+        Reactor   ,"reactor";
+    }
+}
+
+impl UnitControlCommand {
+    /// 最后几个参数是输出的变量
+    pub fn nr_outputs(&self) -> usize {
+        match self {
+            Self::GetBlock => 3,
+            Self::Within => 1,
+            _ => 0,
+        }
+    }
+}
+
+impl LookUp {
+    /// 对应种类的总数，如`@itemCount`
+    pub fn counter(&self) -> String {
+        format!("@{}Count", self.name())
+    }
+}
@@ -2,7 +2,7 @@ use std::fmt::Display;
 
 use crate::{
     error::{Error, ErrorKind},
-    lexer::{Symbol, Token},
+    lexer::{Location, Symbol, Token, TokenVul},
     parser::Parser,
     syn::CompileUnit,
 };
@@ -87,6 +87,11 @@ pub enum Expr<'a> {
         token: &'a Token,
         vul: f64,
     },
+    /// 颜色常量 rgba8888
+    Color {
+        token: &'a Token,
+        vul: u32,
+    },
     Str {
         token: &'a Token,
         vul: &'a str,
@@ -105,6 +110,28 @@ pub enum Expr<'a> {
         fn_name: &'a str,
         args: Vec<Expr<'a>>,
     },
+    /// 读取建筑的属性 `container1.@copper`
+    Sensor {
+        target: Box<Expr<'a>>,
+        attrib_token: &'a Token,
+        attrib: &'a str,
+    },
+}
+
+impl Expr<'_> {
+    /// 表达式开头的位置，用于报错
+    pub fn location(&self) -> Location {
+        match self {
+            Expr::Var { token, .. }
+            | Expr::Num { token, .. }
+            | Expr::Color { token, .. }
+            | Expr::Str { token, .. } => token.location,
+            Expr::Op1 { op, .. } => op.token().location,
+            Expr::Op2 { lv, .. } => lv.location(),
+            Expr::FnCall { fn_name_token, .. } => fn_name_token.location,
+            Expr::Sensor { target, .. } => target.location(),
+        }
+    }
 }
 
 impl std::ops::Neg for &mut Expr<'_> {
@@ -158,7 +185,7 @@ where
         if let Ok(unit) = p.try_parse(parser).finish(ErrorKind::none) {
             units.push(unit);
             // 如果紧接着是一个',' 表示之后可能还有单元
-            if p.try_parse(|p| p.match_symbol(&Symbol::BarcketL, ErrorKind::none))
+            if p.try_parse(|p| p.match_symbol(&Symbol::Split, ErrorKind::none))
                 .finish(ErrorKind::none)
                 .is_ok()
             {
@@ -207,6 +234,10 @@ impl ParserUnit for Expr<'_> {
             let (token, &vul) = p.get_number(ErrorKind::none)?;
             Ok(Expr::Num { token, vul })
         }
+        fn atomic_color(p: &mut Parser) -> Result<Expr<'static>, Error> {
+            let (token, &vul) = p.get_color(ErrorKind::none)?;
+            Ok(Expr::Color { token, vul })
+        }
         fn atomic_string(p: &mut Parser) -> Result<Expr<'static>, Error> {
             let (token, vul) = p.get_string(ErrorKind::none)?;
             Ok(Expr::Str { token, vul })
@@ -222,7 +253,7 @@ impl ParserUnit for Expr<'_> {
             let op = p.try_parse(Op::parse).finish(ErrorKind::none)?;
             match &op {
                 Op::Op { symbol, .. } if symbol.is_unary() => {
-                    let rv = Box::new(atomic_expr(p)?);
+                    let rv = Box::new(postfix_expr(p)?);
                     Ok(Expr::Op1 { op, rv })
                 }
                 Op::AssOp { token, .. } | Op::Op { token, .. } => {
@@ -235,38 +266,63 @@ impl ParserUnit for Expr<'_> {
         }
         fn note() -> String {
             format!(
-                "{}\n\t{}\n\t{}\n\t{}\n\t{}\n\t{}\n",
+                "{}\n\t{}\n\t{}\n\t{}\n\t{}\n\t{}\n\t{}\n",
                 "普通表达式：以下语法之一",
                 "数字",
                 "变量",
                 "一元运算符 表达式",
                 "函数名(参数,...)",
                 "(表达式)",
+                "表达式.@属性",
             )
         }
 
         fn atomic_expr(p: &mut Parser) -> Result<Expr<'static>, Error> {
             p.try_parse(parse_fn_call)
                 .or_try_parse(atomic_num)
+                .or_try_parse(atomic_color)
                 .or_try_parse(atomic_var)
                 .or_try_parse(atomic_string)
-                .or_try_parse(unary_expr)
+                // 不是运算符的符号会让unary_expr给出阻塞的错误，括号要先尝试
                 .or_try_parse(bracket)
+                .or_try_parse(unary_expr)
                 .with_note(note)
                 .finish(generate_error)
         }
 
         // return fn_call(p);
 
+        /// 后缀表达式，目前只有读取属性
+        fn postfix_expr(p: &mut Parser) -> Result<Expr<'static>, Error> {
+            let mut expr = atomic_expr(p)?;
+            while p
+                .try_parse(|p| p.match_symbol(&Symbol::Dot, ErrorKind::none))
+                .finish(ErrorKind::none)
+                .is_ok()
+            {
+                let error = || ErrorKind::not("`@`开头的属性");
+                let (attrib_token, attrib) = p.get_ident(error)?;
+                if !attrib.starts_with('@') {
+                    return Err(p.l_err(error, attrib_token));
+                }
+                expr = Expr::Sensor {
+                    target: Box::new(expr),
+                    attrib_token,
+                    attrib,
+                };
+            }
+            Ok(expr)
+        }
+
         // 第一个表达式不计错误
-        let mut exprs = vec![atomic_expr(p)?];
+        let mut exprs = vec![postfix_expr(p)?];
         let mut ops = vec![];
         while let Ok(op) = p.try_parse(Op::parse).finish(ErrorKind::none) {
             if let Op::AssOp { token, .. } = &op {
                 return Err(p.l_err(|| ErrorKind::not("普通运算符"), token));
             }
             ops.push(op);
-            exprs.push(atomic_expr(p)?);
+            exprs.push(postfix_expr(p)?);
         }
 
         macro_rules! priority {
@@ -317,7 +373,8 @@ impl Display for Expr<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Expr::Var { name, .. } => write!(f, "{name}"),
-            Expr::Num { vul, .. } => write!(f, "{vul}"),
+            Expr::Num { vul, .. } => write!(f, "{}", crate::mlog::number(*vul)),
+            Expr::Color { vul, .. } => write!(f, "{}", crate::mlog::color(*vul)),
             Expr::Str { vul, .. } => write!(f, "\"{vul}\""),
             Expr::Op1 { op, rv } => write!(f, "{op}{rv}"),
             Expr::Op2 { lv, op, rv } => write!(f, "({lv}{op}{rv})"),
@@ -328,6 +385,7 @@ impl Display for Expr<'_> {
                 }
                 write!(f, ")")
             }
+            Expr::Sensor { target, attrib, .. } => write!(f, "{target}.{attrib}"),
         }
     }
 }
@@ -335,12 +393,10 @@ impl Display for Expr<'_> {
 #[derive(Debug, Clone)]
 pub enum Bind<'a> {
     Define {
-        r#let: &'a Token,
         var_tokens: Vec<&'a Token>,
         vars: Vec<&'a str>,
     },
     Init {
-        r#let: &'a Token,
         var_tokens: Vec<&'a Token>,
         vars: Vec<&'a str>,
         ass_op: Op<'a>,
@@ -395,18 +451,14 @@ impl ParserUnit for Bind<'_> {
         }
 
         fn with_let(p: &mut Parser) -> Result<Bind<'static>, Error> {
-            let r#let = p.match_ident(&"let".to_string(), ErrorKind::none)?;
+            p.match_ident(&"let".to_string(), ErrorKind::none)?;
             let (var_tokens, vars) = get_splitd_idents_tokens(p)?;
             // 先匹配换行
             match p
                 .try_parse(|p| p.match_endlines())
                 .finish(|| ErrorKind::not_one_of(&["赋值运算符", "换行"]))
             {
-                Ok(..) => Ok(Bind::Define {
-                    r#let,
-                    var_tokens,
-                    vars,
-                }),
+                Ok(..) => Ok(Bind::Define { var_tokens, vars }),
                 Err(..) => {
                     let ass_op = Op::parse(p)?;
                     match &ass_op {
@@ -419,7 +471,6 @@ impl ParserUnit for Bind<'_> {
                             p.match_endlines()?;
 
                             Ok(Bind::Init {
-                                r#let,
                                 var_tokens,
                                 vars,
                                 ass_op,
@@ -535,32 +586,20 @@ impl Display for Bind<'_> {
 }
 
 #[derive(Debug)]
-pub struct Block<'a> {
-    // pub space: usize,
-    pub start: &'a Token,
+pub struct Block {
     pub stmts: Vec<Box<dyn CompileUnit>>,
-    pub end: &'a Token,
 }
 
-impl ParserUnit for Block<'_> {
+impl ParserUnit for Block {
     fn parse(p: &mut Parser) -> Result<Self, Error> {
-        // static SPACE_INDEX: atomic::AtomicUsize = atomic::AtomicUsize::new(1);
-        let start = p.match_symbol(&Symbol::SpaceL, ErrorKind::none)?;
-        // let index = SPACE_INDEX.fetch_add(1, atomic::Ordering::Relaxed);
-
+        p.match_symbol(&Symbol::SpaceL, ErrorKind::none)?;
         let stmts = parse_compile_units(p)?;
-        let end = p.match_symbol(&Symbol::SpaceR, ErrorKind::unexpect)?;
-
-        Ok(Self {
-            // space: index,
-            start,
-            stmts,
-            end,
-        })
+        p.match_symbol(&Symbol::SpaceR, ErrorKind::unexpect)?;
+        Ok(Self { stmts })
     }
 }
 
-impl Display for Block<'_> {
+impl Display for Block {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{{")?;
         for stmt in &self.stmts {
@@ -575,14 +614,38 @@ pub enum ControlFlow<'a> {
     If {
         r#if: &'a Token,
         conditions: Vec<Expr<'a>>,
-        blocks: Vec<Block<'a>>,
+        blocks: Vec<Block>,
         r#else: Option<&'a Token>,
-        else_block: Option<Block<'a>>,
+        else_block: Option<Block>,
     },
     While {
+        label: Option<Label<'a>>,
         r#while: &'a Token,
         condition: Expr<'a>,
-        block: Block<'a>,
+        block: Block,
+    },
+    /// `loop {}`，只能用break跳出
+    Loop {
+        label: Option<Label<'a>>,
+        r#loop: &'a Token,
+        block: Block,
+    },
+    /// `do {} while cond`，先执行一次再判断条件
+    DoWhile {
+        label: Option<Label<'a>>,
+        r#do: &'a Token,
+        block: Block,
+        condition: Expr<'a>,
+    },
+    /// `for i in start..end [step n]`，区间左闭右开
+    For {
+        label: Option<Label<'a>>,
+        var_token: &'a Token,
+        var: &'a str,
+        start: Expr<'a>,
+        end: Expr<'a>,
+        step: Option<Expr<'a>>,
+        block: Block,
     },
 }
 
@@ -650,12 +713,14 @@ impl ParserUnit for ControlFlow<'_> {
             .finish(ErrorKind::none)
         })
         .or_try_parse(|p| {
+            let label = Label::parse_before_loop(p);
             let r#while = p.match_ident(&"while".to_string(), || ErrorKind::None)?;
-            p.try_parse(|p| {
+            p.try_parse(move |p| {
                 let condition = Expr::parse(p)?;
                 let block = Block::parse(p)?;
                 p.match_endlines()?;
                 Ok(Self::While {
+                    label,
                     r#while,
                     condition,
                     block,
@@ -664,6 +729,75 @@ impl ParserUnit for ControlFlow<'_> {
             .with_note(|| format!("{}\n\t{}\n", "while用法：", "while 条件 代码块"))
             .finish(ErrorKind::none)
         })
+        .or_try_parse(|p| {
+            let label = Label::parse_before_loop(p);
+            let r#loop = p.match_ident(&"loop".to_string(), || ErrorKind::None)?;
+            p.try_parse(move |p| {
+                let block = Block::parse(p)?;
+                p.match_endlines()?;
+                Ok(Self::Loop {
+                    label,
+                    r#loop,
+                    block,
+                })
+            })
+            .with_note(|| format!("{}\n\t{}\n", "loop用法：", "loop 代码块"))
+            .finish(ErrorKind::none)
+        })
+        .or_try_parse(|p| {
+            let label = Label::parse_before_loop(p);
+            let r#do = p.match_ident(&"do".to_string(), || ErrorKind::None)?;
+            p.try_parse(move |p| {
+                let block = Block::parse(p)?;
+                p.match_ident(&"while".to_string(), || ErrorKind::not("while"))?;
+                let condition = Expr::parse(p)?;
+                p.match_endlines()?;
+                Ok(Self::DoWhile {
+                    label,
+                    r#do,
+                    block,
+                    condition,
+                })
+            })
+            .with_note(|| format!("{}\n\t{}\n", "do while用法：", "do 代码块 while 条件"))
+            .finish(ErrorKind::none)
+        })
+        .or_try_parse(|p| {
+            let label = Label::parse_before_loop(p);
+            p.match_ident(&"for".to_string(), || ErrorKind::None)?;
+            p.try_parse(move |p| {
+                let (var_token, var) = p.get_ident(|| ErrorKind::not("循环变量"))?;
+                p.match_ident(&"in".to_owned(), || ErrorKind::not("in"))?;
+                let start = Expr::parse(p)?;
+                p.match_symbol(&Symbol::Range, || ErrorKind::not(".."))?;
+                let end = Expr::parse(p)?;
+                let step = match p
+                    .try_parse(|p| p.match_ident(&"step".to_owned(), ErrorKind::none))
+                    .finish(ErrorKind::none)
+                {
+                    Ok(_) => Some(Expr::parse(p)?),
+                    Err(_) => None,
+                };
+                let block = Block::parse(p)?;
+                p.match_endlines()?;
+                Ok(Self::For {
+                    label,
+                    var_token,
+                    var,
+                    start,
+                    end,
+                    step,
+                    block,
+                })
+            })
+            .with_note(|| {
+                format!(
+                    "{}\n\t{}\n",
+                    "for用法：", "for 变量 in 起点..终点 [step 步长] 代码块"
+                )
+            })
+            .finish(ErrorKind::none)
+        })
         .finish(ErrorKind::none)
     }
 }
@@ -692,26 +826,68 @@ impl Display for ControlFlow<'_> {
                 Ok(())
             }
             ControlFlow::While {
-                condition, block, ..
-            } => write!(f, "while {} {}", condition, block),
+                label,
+                condition,
+                block,
+                ..
+            } => {
+                if let Some(label) = label {
+                    write!(f, "{label}: ")?;
+                }
+                write!(f, "while {} {}", condition, block)
+            }
+            ControlFlow::Loop { label, block, .. } => {
+                if let Some(label) = label {
+                    write!(f, "{label}: ")?;
+                }
+                write!(f, "loop {block}")
+            }
+            ControlFlow::DoWhile {
+                label,
+                block,
+                condition,
+                ..
+            } => {
+                if let Some(label) = label {
+                    write!(f, "{label}: ")?;
+                }
+                write!(f, "do {block} while {condition}")
+            }
+            ControlFlow::For {
+                label,
+                var,
+                start,
+                end,
+                step,
+                block,
+                ..
+            } => {
+                if let Some(label) = label {
+                    write!(f, "{label}: ")?;
+                }
+                write!(f, "for {var} in {start}..{end}")?;
+                if let Some(step) = step {
+                    write!(f, " step {step}")?;
+                }
+                write!(f, " {block}")
+            }
         }
     }
 }
 
 #[derive(Debug)]
 pub struct FnDef<'a> {
-    r#fn: &'a Token,
-    fn_name_token: &'a Token,
-    fn_name: &'a str,
-    parm_tokens: Vec<&'a Token>,
-    parms: Vec<&'a str>,
-    block: Block<'a>,
+    pub fn_name_token: &'a Token,
+    pub fn_name: &'a str,
+    pub parm_tokens: Vec<&'a Token>,
+    pub parms: Vec<&'a str>,
+    pub block: Block,
 }
 
 impl ParserUnit for FnDef<'_> {
     fn parse(p: &mut Parser) -> Result<Self, Error> {
         p.try_parse(|p| {
-            let r#fn = p.match_ident(&"fn".to_string(), ErrorKind::none)?;
+            p.match_ident(&"fn".to_string(), ErrorKind::none)?;
             let (fn_name_token, fn_name) = p.get_ident(|| ErrorKind::not("标识符"))?;
 
             let get_parm_token = |p: &mut Parser| {
@@ -734,7 +910,6 @@ impl ParserUnit for FnDef<'_> {
             // ));
 
             Ok(Self {
-                r#fn,
                 fn_name_token,
                 fn_name,
                 parm_tokens,
@@ -754,6 +929,126 @@ impl Display for FnDef<'_> {
     }
 }
 
+#[derive(Debug)]
+pub struct Return<'a> {
+    pub r#return: &'a Token,
+    pub vul: Option<Expr<'a>>,
+}
+
+impl ParserUnit for Return<'_> {
+    fn parse(p: &mut Parser) -> Result<Self, Error> {
+        p.try_parse(|p| {
+            let r#return = p.match_ident(&"return".to_string(), ErrorKind::none)?;
+            // 没有返回值时直接换行
+            if p.try_parse(|p| p.match_endlines())
+                .finish(ErrorKind::none)
+                .is_ok()
+            {
+                return Ok(Self {
+                    r#return,
+                    vul: None,
+                });
+            }
+            let vul = Expr::parse(p)?;
+            p.match_endlines()?;
+            Ok(Self {
+                r#return,
+                vul: Some(vul),
+            })
+        })
+        .with_note(|| format!("{}\n\t{}\n", "return用法：", "return 零或一个表达式"))
+        .finish(ErrorKind::none)
+    }
+}
+
+impl Display for Return<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.vul {
+            Some(vul) => write!(f, "return {vul}"),
+            None => write!(f, "return"),
+        }
+    }
+}
+
+/// 循环的标签 `'outer`
+#[derive(Debug, Clone, Copy)]
+pub struct Label<'a> {
+    pub token: &'a Token,
+    pub name: &'a str,
+}
+
+impl Label<'_> {
+    fn parse(p: &mut Parser) -> Result<Self, Error> {
+        let (token, name) = p.get_ident(ErrorKind::none)?;
+        if !name.starts_with('\'') {
+            return Err(p.l_err(ErrorKind::none, token));
+        }
+        Ok(Self { token, name })
+    }
+
+    /// 循环之前可选的`'label:`
+    fn parse_before_loop(p: &mut Parser) -> Option<Self> {
+        p.try_parse(|p| {
+            let label = Self::parse(p)?;
+            p.match_symbol(&Symbol::Colon, ErrorKind::none)?;
+            Ok(label)
+        })
+        .finish(ErrorKind::none)
+        .ok()
+    }
+}
+
+impl Display for Label<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// `break`和`continue`，可以带上要跳出的循环的标签
+#[derive(Debug)]
+pub struct LoopJump<'a> {
+    pub token: &'a Token,
+    pub r#break: bool,
+    pub label: Option<Label<'a>>,
+}
+
+impl ParserUnit for LoopJump<'_> {
+    fn parse(p: &mut Parser) -> Result<Self, Error> {
+        p.try_parse(|p| {
+            let (token, keyword) = p.get_ident(ErrorKind::none)?;
+            let r#break = match keyword.as_str() {
+                "break" => true,
+                "continue" => false,
+                _ => return Err(p.l_err(ErrorKind::none, token)),
+            };
+            let label = p.try_parse(Label::parse).finish(ErrorKind::none).ok();
+            p.match_endlines()?;
+            Ok(Self {
+                token,
+                r#break,
+                label,
+            })
+        })
+        .with_note(|| {
+            format!(
+                "{}\n\t{}\n",
+                "break/continue用法：", "break/continue 零或一个标签"
+            )
+        })
+        .finish(ErrorKind::none)
+    }
+}
+
+impl Display for LoopJump<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let keyword = if self.r#break { "break" } else { "continue" };
+        match &self.label {
+            Some(label) => write!(f, "{keyword} {label}"),
+            None => write!(f, "{keyword}"),
+        }
+    }
+}
+
 fn trim_endlines(p: &mut Parser) -> Result<crate::syn::EmptyStmt, Error> {
     p.try_parse(|p| p.match_endlines())
         .finish(ErrorKind::none)
@@ -771,22 +1066,58 @@ pub fn parse_compile_unit(p: &mut Parser) -> Result<Box<dyn CompileUnit>, Error>
             |p| Ok(Box::new(($e)(p)?) as Box<dyn CompileUnit>)
         };
     }
-    p.try_parse(cu_box!(parse_fn_call_stmt))
+    // `return (x)`会被当成函数调用，所以Return要放在最前面
+    p.try_parse(cu_box!(Return::parse))
+        .or_try_parse(cu_box!(LoopJump::parse))
+        .or_try_parse(cu_box!(parse_fn_call_stmt))
         .or_try_parse(cu_box!(trim_endlines))
         .or_try_parse(cu_box!(ControlFlow::parse))
         .or_try_parse(cu_box!(Block::parse))
         .or_try_parse(cu_box!(FnDef::parse))
         .or_try_parse(cu_box!(Bind::parse))
-        .with_note(|| "?".to_string())
-        .finish(|| ErrorKind::not_one_of(&["Bind", "If", "While", "FnCall"]))
+        .with_note(|| {
+            format!(
+                "{}\n\t{}\n\t{}\n\t{}\n\t{}\n\t{}\n\t{}\n\t{}\n\t{}\n\t{}\n\t{}\n",
+                "语句：以下语法之一",
+                "let var1, ..varn = vul1, ..vuln",
+                "var1, ..varn = vul1, ..vuln",
+                "if/[标签:] while 条件 代码块",
+                "[标签:] loop 代码块",
+                "[标签:] do 代码块 while 条件",
+                "[标签:] for 变量 in 起点..终点 [step 步长] 代码块",
+                "fn 标识符(标识符,...) 代码块",
+                "函数名(参数,...)",
+                "return 表达式",
+                "break/continue [标签]",
+            )
+        })
+        .finish(|| {
+            ErrorKind::not_one_of(&[
+                "Bind", "If", "While", "Loop", "DoWhile", "For", "FnCall", "Return", "Break",
+                "Continue",
+            ])
+        })
 }
 
 pub fn parse_compile_units(p: &mut Parser) -> Result<Vec<Box<dyn CompileUnit>>, Error> {
     let mut compile_units = vec![];
     // 因为Block::parse会处理 “}”
-    // 对于任何错误 直接break 交给外层处理
-    while let Ok(compile_unit) = parse_compile_unit(p) {
-        compile_units.push(compile_unit)
+    // 遇到“}”或者结尾时 直接break 交给外层处理
+    // 其他错误记录下来，跳过出错的语句继续解析
+    loop {
+        match parse_compile_unit(p) {
+            Ok(compile_unit) => compile_units.push(compile_unit),
+            Err(e) => {
+                let at_end = p
+                    .peek_token()
+                    .is_none_or(|token| token.vul == TokenVul::Symbol(Symbol::SpaceR));
+                if at_end {
+                    break;
+                }
+                p.report(e);
+                p.synchronize();
+            }
+        }
     }
 
     // let _ = p.try_parse(|p| p.match_endlines()).finish(ErrorKind::none);
@@ -797,13 +1128,13 @@ pub fn parse_compile_units(p: &mut Parser) -> Result<Vec<Box<dyn CompileUnit>>,
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::tokens;
 
     fn parser_test<T, F>(src: &str, f: F) -> Result<T, Error>
     where
         F: FnOnce(&mut Parser) -> Result<T, Error> + 'static,
     {
-        let tokens = crate::lexer::Lexer::new(src).toekns();
-        let mut parser = crate::parser::Parser::new(&tokens);
+        let mut parser = crate::parser::Parser::new(tokens(src));
         let result = parser.try_parse(f).finish(ErrorKind::none);
 
         result
@@ -815,7 +1146,11 @@ mod tests {
 
         let r = parser_test(src, Expr::parse);
         assert!(r.is_ok());
-        assert!(r.unwrap().to_string() == "((1+(2*3))+!main())")
+        assert!(r.unwrap().to_string() == "((1+(2*3))+!main())");
+
+        let src = r"dist(x, y * 2, f())";
+        let r = parser_test(src, Expr::parse);
+        assert_eq!(r.unwrap().to_string(), "dist(x,(y*2),f(),)");
     }
 
     #[test]
@@ -839,6 +1174,18 @@ mod tests {
 
         let src = "while y {}";
         assert!(parser_test(src, ControlFlow::parse).is_ok());
+
+        let src = "for i in 0..n step 2 {}";
+        assert!(parser_test(src, ControlFlow::parse).is_ok());
+
+        let src = "'outer: while z {}";
+        assert!(parser_test(src, ControlFlow::parse).is_ok());
+
+        let src = "loop {}";
+        assert!(parser_test(src, ControlFlow::parse).is_ok());
+
+        let src = "do {} while w";
+        assert!(parser_test(src, ControlFlow::parse).is_ok());
     }
 
     #[test]
@@ -856,4 +1203,40 @@ mod tests {
         dbg!(&r);
         assert!(r.is_ok());
     }
+
+    #[test]
+    fn empty() {
+        for src in ["", "\n\n"] {
+            let units = crate::testing::parse(src);
+            assert!(units.iter().all(|u| u.to_string().is_empty()));
+        }
+        // 不完整的语句在结尾出错
+        let (_, errors) = Parser::new(tokens("let")).get_compile_units();
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn recovery() {
+        let src = r"
+            let x = (1
+            if x {
+                x = 1 +
+                x = 2
+            }
+            }
+            let y = 3
+            while y { y = $ }
+        ";
+        let (units, errors) = Parser::new(tokens(src)).get_compile_units();
+        let lines: Vec<_> = errors
+            .iter()
+            .map(|e| e.location.unwrap().display_location().0)
+            .collect();
+        assert_eq!(lines, [2, 4, 7, 9]);
+        // if、let y 和 while 被保留
+        assert_eq!(
+            units.iter().filter(|u| !u.to_string().is_empty()).count(),
+            3
+        );
+    }
 }
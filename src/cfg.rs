@@ -0,0 +1,557 @@
+//! 控制流图
+//!
+//! 代码生成直接得到以标签跳转的基本块，优化在控制流图上进行，
+//! 输出mlog之前才重新排布为带有绝对跳转目标的语句
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Debug, Display},
+};
+
+use crate::{
+    abi::{JumpCondition, Statement, Variable, VariableName, VariableValue},
+    error::{Warn, WarnKind},
+    lexer::Location,
+    syn::{Mark, Marked, Statements, DRAW_BUFFER},
+};
+
+/// 基本块的标签，全局唯一，和块在布局中的位置无关
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label(usize);
+
+impl Label {
+    pub fn alloc() -> Self {
+        use std::sync::atomic;
+        static LABEL_INDEX: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+        Self(LABEL_INDEX.fetch_add(1, atomic::Ordering::Relaxed))
+    }
+}
+
+impl Display for Label {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "L{}", self.0)
+    }
+}
+
+impl Debug for Label {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// 基本块的出口，跳转目标都是标签
+#[derive(Debug, Clone)]
+pub enum Exit {
+    /// 无条件跳转，目标是布局中的下一个块时不需要生成跳转
+    Goto(Label),
+    /// 条件成立时跳转到`then`，否则到`r#else`
+    Branch {
+        left: Variable,
+        cond: JumpCondition,
+        right: Variable,
+        then: Label,
+        r#else: Label,
+    },
+    /// 调用函数
+    ///
+    /// 生成为`op add addr @counter 1`和`jump entry always`，函数返回到`ret`
+    Call {
+        id: usize,
+        addr: Variable,
+        entry: Label,
+        ret: Label,
+    },
+    /// 块的最后一条语句修改了`@counter`，是函数返回时为函数的编号
+    Indirect(Option<usize>),
+    End,
+    Stop,
+}
+
+impl Exit {
+    pub fn operands_mut(&mut self) -> Vec<&mut Variable> {
+        match self {
+            Self::Branch { left, right, .. } => vec![left, right],
+            _ => vec![],
+        }
+    }
+
+    pub fn results(&self) -> Vec<&Variable> {
+        match self {
+            Self::Call { addr, .. } => vec![addr],
+            _ => vec![],
+        }
+    }
+
+    /// 可能跳转到的标签，函数返回的位置需要整个控制流图才能知道
+    pub fn targets(&self) -> Vec<Label> {
+        match self {
+            Self::Goto(target) => vec![*target],
+            Self::Branch { then, r#else, .. } => vec![*then, *r#else],
+            Self::Call { entry, .. } => vec![*entry],
+            Self::Indirect(..) | Self::End | Self::Stop => vec![],
+        }
+    }
+
+    pub fn targets_mut(&mut self) -> Vec<&mut Label> {
+        match self {
+            Self::Goto(target) => vec![target],
+            Self::Branch { then, r#else, .. } => vec![then, r#else],
+            Self::Call { entry, ret, .. } => vec![entry, ret],
+            Self::Indirect(..) | Self::End | Self::Stop => vec![],
+        }
+    }
+
+    /// 排布时生成的语句数，`next`是布局中的下一个块
+    pub fn len(&self, next: Label) -> usize {
+        match *self {
+            Self::Goto(target) => (target != next) as usize,
+            Self::Branch { then, r#else, .. } if then == r#else => (then != next) as usize,
+            Self::Branch {
+                ref cond,
+                then,
+                r#else,
+                ..
+            } if then == next && cond.negate().is_some() => (r#else != next) as usize,
+            Self::Branch { r#else, .. } => 1 + (r#else != next) as usize,
+            Self::Call { ret, .. } => 2 + (ret != next) as usize,
+            Self::Indirect(..) => 0,
+            Self::End | Self::Stop => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub label: Label,
+    /// 不包括出口的语句
+    pub stmts: Vec<Statement>,
+    /// draw指令等的标记，序号是在块中的位置
+    pub marks: Vec<(usize, Mark)>,
+    pub exit: Exit,
+}
+
+impl Block {
+    /// 只保留`keep`为true的语句，标记会随之调整
+    pub fn retain<F>(&mut self, mut keep: F) -> bool
+    where
+        F: FnMut(&Statement) -> bool,
+    {
+        let keep: Vec<_> = self.stmts.iter().map(&mut keep).collect();
+        if keep.iter().all(|&keep| keep) {
+            return false;
+        }
+        let mut new_index = Vec::with_capacity(keep.len());
+        let mut count = 0;
+        for &keep in &keep {
+            new_index.push(count);
+            count += keep as usize;
+        }
+
+        let mut keep_iter = keep.iter();
+        self.stmts.retain(|_| *keep_iter.next().unwrap());
+        self.marks = std::mem::take(&mut self.marks)
+            .into_iter()
+            .filter(|&(index, _)| keep[index])
+            .map(|(index, mark)| (new_index[index], mark))
+            .collect();
+        true
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    /// 按照布局的顺序，第一个块是程序的入口
+    blocks: Vec<Block>,
+}
+
+impl Cfg {
+    /// 应在`link_fns`之后调用，执行到最后一个块之后会回到第一个块
+    ///
+    /// 修改了`@counter`的语句，以及`end`和`stop`，也会结束所在的块
+    pub fn new(stmts: Statements) -> Self {
+        let mut blocks = vec![];
+        for mut block in stmts.into_blocks() {
+            loop {
+                let last = block.stmts.len().saturating_sub(1);
+                let split = block.stmts.iter().position(|stmt| {
+                    writes_counter(stmt) || matches!(stmt, Statement::End | Statement::Stop)
+                });
+                let index = match split {
+                    // 函数返回时已经是块的出口了
+                    Some(index) if index != last || !matches!(block.exit, Exit::Indirect(..)) => {
+                        index
+                    }
+                    _ => break,
+                };
+                let mut rest = Block {
+                    label: Label::alloc(),
+                    stmts: block.stmts.split_off(index + 1),
+                    marks: vec![],
+                    exit: Exit::End,
+                };
+                let (marks, rest_marks) = std::mem::take(&mut block.marks)
+                    .into_iter()
+                    .partition(|&(at, _)| at <= index);
+                block.marks = marks;
+                rest.marks = rest_marks
+                    .into_iter()
+                    .map(|(at, mark)| (at - index - 1, mark))
+                    .collect();
+
+                let exit = match block.stmts[index] {
+                    Statement::End => Exit::End,
+                    Statement::Stop => Exit::Stop,
+                    _ => Exit::Indirect(None),
+                };
+                if !matches!(exit, Exit::Indirect(..)) {
+                    block.stmts.pop();
+                    block.marks.retain(|&(at, _)| at != index);
+                }
+                rest.exit = std::mem::replace(&mut block.exit, exit);
+                blocks.push(std::mem::replace(&mut block, rest));
+            }
+            blocks.push(block);
+        }
+        Self { blocks }
+    }
+
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    pub fn blocks_mut(&mut self) -> &mut [Block] {
+        &mut self.blocks
+    }
+
+    /// 只保留`keep`为true的块，第一个块必须被保留
+    pub fn retain(&mut self, keep: &[bool]) {
+        assert!(keep.first().is_none_or(|&keep| keep), "不能移除入口");
+        let mut keep = keep.iter();
+        self.blocks.retain(|_| *keep.next().unwrap());
+    }
+
+    /// 每个标签对应的块在布局中的位置
+    pub fn positions(&self) -> HashMap<Label, usize> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .map(|(index, block)| (block.label, index))
+            .collect()
+    }
+
+    /// 每个块之后可能执行的块
+    ///
+    /// * 函数返回时会回到每一个调用处之后
+    /// * 其他对`@counter`的赋值可能跳转到任何地方
+    pub fn successors(&self) -> Vec<Vec<usize>> {
+        let positions = self.positions();
+        let mut return_sites: HashMap<usize, Vec<usize>> = HashMap::new();
+        for block in &self.blocks {
+            if let Exit::Call { id, ret, .. } = block.exit {
+                return_sites.entry(id).or_default().push(positions[&ret]);
+            }
+        }
+
+        self.blocks
+            .iter()
+            .map(|block| match block.exit {
+                Exit::Indirect(Some(id)) => return_sites.get(&id).cloned().unwrap_or_default(),
+                Exit::Indirect(None) => (0..self.blocks.len()).collect(),
+                Exit::End => vec![0],
+                _ => block
+                    .exit
+                    .targets()
+                    .iter()
+                    .map(|target| positions[target])
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// 按照布局排布为语句，跳转到下一个块的跳转会被省略
+    pub fn lower(&self) -> Program {
+        let len = self.blocks.len();
+        let positions = self.positions();
+        let next = |index: usize| self.blocks[(index + 1) % len].label;
+
+        let mut addresses = Vec::with_capacity(len);
+        let mut address = 0;
+        for (index, block) in self.blocks.iter().enumerate() {
+            addresses.push(address);
+            address += block.stmts.len() + block.exit.len(next(index));
+        }
+        let address = |label: &Label| addresses[positions[label]];
+
+        let mut stmts = Program::default();
+        for (index, block) in self.blocks.iter().enumerate() {
+            for (offset, stmt) in block.stmts.iter().enumerate() {
+                stmts.push_stmt(stmt.clone());
+                for &(_, mark) in block.marks.iter().filter(|(at, _)| *at == offset) {
+                    stmts.mark_last(mark);
+                }
+            }
+
+            let next = next(index);
+            let goto = |stmts: &mut Program, target: &Label| {
+                if *target != next {
+                    stmts.jump_always(address(target));
+                }
+            };
+            match &block.exit {
+                Exit::Goto(target) => goto(&mut stmts, target),
+                Exit::Branch { then, r#else, .. } if then == r#else => goto(&mut stmts, then),
+                Exit::Branch {
+                    left,
+                    cond,
+                    right,
+                    then,
+                    r#else,
+                } => match cond.negate() {
+                    // 条件成立时执行下一个块，反过来跳转到else
+                    Some(negated) if *then == next => {
+                        stmts.push_stmt(Statement::jump(
+                            left.clone(),
+                            negated,
+                            right.clone(),
+                            address(r#else),
+                        ));
+                    }
+                    _ => {
+                        stmts.push_stmt(Statement::jump(
+                            left.clone(),
+                            cond.clone(),
+                            right.clone(),
+                            address(then),
+                        ));
+                        goto(&mut stmts, r#else);
+                    }
+                },
+                Exit::Call {
+                    id,
+                    addr,
+                    entry,
+                    ret,
+                } => {
+                    // 执行op时@counter已经指向了下一条语句，也就是jump
+                    // ret不在之后时，返回到jump之后的跳转
+                    stmts.push_stmt(Statement::operation(
+                        addr.clone(),
+                        crate::lexer::Symbol::Add,
+                        Variable::counter(),
+                        Variable::new(VariableName::None, VariableValue::Number(1.0)),
+                    ));
+                    stmts.jump_always(address(entry));
+                    stmts.mark_last(Mark::Call(*id));
+                    goto(&mut stmts, ret);
+                }
+                Exit::Indirect(..) => {}
+                Exit::End => stmts.push_stmt(Statement::End),
+                Exit::Stop => stmts.push_stmt(Statement::Stop),
+            }
+        }
+        stmts
+    }
+}
+
+/// 排布好的语句，跳转的目标是语句的序号
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    code: Marked,
+}
+
+impl Program {
+    pub fn iter(&self) -> std::slice::Iter<'_, Statement> {
+        self.code.stmts.iter()
+    }
+
+    pub fn push_stmt(&mut self, stmt: Statement) {
+        self.code.push_stmt(stmt);
+    }
+
+    pub fn mark_last(&mut self, mark: Mark) {
+        self.code.mark_last(mark);
+    }
+
+    pub fn jump_always(&mut self, target: usize) {
+        self.push_stmt(Statement::jump(
+            Variable::zero(),
+            JumpCondition::Always,
+            Variable::zero(),
+            target,
+        ));
+    }
+
+    /// 检查绘图缓冲区是否可能溢出
+    ///
+    /// 循环的次数是未知的，所以只沿着向前的跳转计算每条路径上drawflush之前的draw的数量，
+    /// 调用函数时函数中的draw也会计入调用之后的部分
+    pub fn check_draw_buffer(&self) -> Vec<Warn> {
+        let mut check = DrawBuffer {
+            program: self,
+            calls: HashSet::new(),
+            draws: HashMap::new(),
+            returns: HashMap::new(),
+            warned: HashSet::new(),
+            warns: vec![],
+        };
+        for &(index, mark) in &self.code.marks {
+            match mark {
+                Mark::Call(..) => {
+                    check.calls.insert(index);
+                }
+                Mark::Draw { location, source } => {
+                    check.draws.insert(index, (location, source));
+                }
+            }
+        }
+        check.flow(0, 0);
+        check.warns
+    }
+}
+
+/// 检查绘图缓冲区时的状态
+struct DrawBuffer<'a> {
+    program: &'a Program,
+    /// 调用函数的跳转
+    calls: HashSet<usize>,
+    draws: HashMap<usize, (Location, usize)>,
+    /// 从函数入口以某个指令数开始执行时，返回时最多有的指令数
+    returns: HashMap<(usize, usize), Option<usize>>,
+    /// 已经警告过的draw，函数被多次调用时只警告一次
+    warned: HashSet<usize>,
+    warns: Vec<Warn>,
+}
+
+impl DrawBuffer<'_> {
+    /// 从`start`开始执行，开始时缓冲区中有`count`条指令
+    ///
+    /// 返回执行到函数返回时缓冲区中最多可能有的指令数，不会返回时为None
+    fn flow(&mut self, start: usize, count: usize) -> Option<usize> {
+        if let Some(&ret) = self.returns.get(&(start, count)) {
+            return ret;
+        }
+        let program = self.program;
+        let stmts = &program.code.stmts;
+        let mut ret = None;
+        // 到达每条语句时缓冲区中最多可能有的指令数，None表示沿着向前的跳转不可达
+        let mut pending = vec![None; stmts.len()];
+        if let Some(first) = pending.get_mut(start) {
+            *first = Some(count);
+        }
+        for (index, stmt) in stmts.iter().enumerate().skip(start) {
+            let Some(count) = pending[index] else {
+                continue;
+            };
+            let count = match stmt {
+                Statement::Draw { .. } => {
+                    // 只在第一次超出时警告
+                    if count == DRAW_BUFFER && self.warned.insert(index) {
+                        if let Some(&(location, source)) = self.draws.get(&index) {
+                            let mut warn = WarnKind::DrawBufferOverflow.make_warn(location);
+                            warn.source = source;
+                            warn.note = "超出的部分会被丢弃".to_owned();
+                            self.warns.push(warn);
+                        }
+                    }
+                    (count + 1).min(DRAW_BUFFER + 1)
+                }
+                Statement::DrawFlush { .. } => 0,
+                _ => count,
+            };
+
+            let mut next = vec![];
+            match stmt {
+                // 函数返回之后接着执行调用之后的语句
+                Statement::Jump { target, .. } if self.calls.contains(&index) => {
+                    if let Some(count) = self.flow(*target, count) {
+                        next.push((index + 1, count));
+                    }
+                }
+                Statement::Jump { cond, target, .. } => {
+                    next.push((*target, count));
+                    if !matches!(cond, JumpCondition::Always) {
+                        next.push((index + 1, count));
+                    }
+                }
+                // 函数返回
+                Statement::Set { result, .. }
+                    if result
+                        .name
+                        .as_named()
+                        .is_some_and(|name| name == "@counter") =>
+                {
+                    ret = ret.max(Some(count));
+                }
+                Statement::Stop | Statement::End => {}
+                _ => next.push((index + 1, count)),
+            }
+            for (next, count) in next {
+                if next > index && next < pending.len() {
+                    pending[next] = pending[next].max(Some(count));
+                }
+            }
+        }
+        self.returns.insert((start, count), ret);
+        ret
+    }
+}
+
+pub fn writes_counter(stmt: &Statement) -> bool {
+    stmt.results().iter().any(|result| {
+        result
+            .name
+            .as_named()
+            .is_some_and(|name| name == "@counter")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let src = "fn f(x) {\n    return x * 2\n}\nlet i = 0\nwhile i < 10 {\n    i = f(i)\n}\nprint(i)\n";
+        let cfg = crate::testing::generate(src, crate::opt::Level::O0).unwrap();
+        // 标签是全局分配的，这里换成块在布局中的位置
+        let positions = cfg.positions();
+        let at = |label: &Label| positions[label];
+        let exits: Vec<_> = cfg
+            .blocks()
+            .iter()
+            .map(|block| match &block.exit {
+                Exit::Goto(target) => format!("goto {}", at(target)),
+                Exit::Branch { then, r#else, .. } => format!("branch {} {}", at(then), at(r#else)),
+                Exit::Call { entry, ret, .. } => format!("call {} {}", at(entry), at(ret)),
+                Exit::Indirect(id) => format!("indirect {}", id.is_some()),
+                Exit::End | Exit::Stop => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            exits,
+            [
+                "goto 1",
+                "branch 4 2",
+                "call 5 3",
+                "goto 1",
+                "goto 0",
+                "indirect true",
+            ]
+        );
+        // 没有优化时按照生成的顺序排布
+        assert_eq!(
+            crate::mlog::emit(&cfg.lower()),
+            "set i 0\n\
+             jump 7 greaterThanEq i 10\n\
+             set f.x i\n\
+             op add f.addr @counter 1\n\
+             jump 9 always 0 0\n\
+             set i f.ret\n\
+             jump 1 always 0 0\n\
+             print i\n\
+             jump 0 always 0 0\n\
+             op mul f.ret f.x 2\n\
+             set @counter f.addr\n"
+        );
+    }
+}
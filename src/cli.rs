@@ -0,0 +1,172 @@
+use std::fmt::Display;
+
+pub const USAGE: &str = "\
+用法: terl [选项] <输入文件>...
+
+输入文件为 - 时从标准输入读取
+
+选项:
+    -o <文件>        输出到文件，默认输出到标准输出
+    --emit <阶段>    输出编译的某个阶段: tokens | ast | ir | mlog (默认)
+    -O0, -Os, -O2    优化等级: 不优化 | 减小代码 (默认) | 减少执行的指令
+    --pass <优化>    启用某个优化，-O0之外默认全部启用
+    --no-pass <优化> 禁用某个优化
+                     优化: const-prop | copy-prop | dse | thread | unreachable
+    -h, --help       显示此帮助";
+
+/// 要输出的编译阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    /// `lexer::Lexer::toekns`的结果
+    Tokens,
+    /// `Parser::get_compile_units`的结果
+    Ast,
+    /// 优化之前的控制流图，语句以mlog的格式输出
+    Ir,
+    /// 最终的mlog
+    Mlog,
+}
+
+impl std::str::FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let emit = match s {
+            "tokens" => Self::Tokens,
+            "ast" => Self::Ast,
+            "ir" => Self::Ir,
+            "mlog" => Self::Mlog,
+            _ => {
+                return Err(format!(
+                    "未知的阶段 `{s}`，应为 tokens | ast | ir | mlog 之一"
+                ))
+            }
+        };
+        Ok(emit)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub inputs: Vec<String>,
+    /// `None`表示输出到标准输出
+    pub output: Option<String>,
+    pub emit: Emit,
+    pub level: crate::opt::Level,
+    /// 在优化等级的基础上，按照出现的顺序启用或禁用的优化
+    pub passes: Vec<(&'static str, bool)>,
+    pub help: bool,
+}
+
+impl Options {
+    pub fn parse<I>(args: I) -> Result<Self, CliError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut options = Self {
+            inputs: vec![],
+            output: None,
+            emit: Emit::Mlog,
+            level: crate::opt::Level::default(),
+            passes: vec![],
+            help: false,
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |option: &str| {
+                args.next()
+                    .ok_or_else(|| CliError(format!("选项 `{option}` 缺少参数")))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => options.help = true,
+                "-o" => {
+                    let output = value("-o")?;
+                    options.output = (output != "-").then_some(output);
+                }
+                "--emit" => options.emit = value("--emit")?.parse().map_err(CliError)?,
+                _ if arg.starts_with("--emit=") => {
+                    options.emit = arg["--emit=".len()..].parse().map_err(CliError)?
+                }
+                "--pass" => options.passes.push((pass(&value("--pass")?)?, true)),
+                "--no-pass" => options.passes.push((pass(&value("--no-pass")?)?, false)),
+                _ if arg.starts_with("-O") => {
+                    options.level = arg["-O".len()..].parse().map_err(CliError)?
+                }
+                _ if arg.starts_with("--pass=") => {
+                    options.passes.push((pass(&arg["--pass=".len()..])?, true))
+                }
+                _ if arg.starts_with("--no-pass=") => options
+                    .passes
+                    .push((pass(&arg["--no-pass=".len()..])?, false)),
+                _ if arg.starts_with('-') && arg != "-" => {
+                    return Err(CliError(format!("未知的选项 `{arg}`")))
+                }
+                _ => options.inputs.push(arg),
+            }
+        }
+
+        if options.inputs.is_empty() && !options.help {
+            return Err(CliError("缺少输入文件".to_owned()));
+        }
+        Ok(options)
+    }
+}
+
+fn pass(name: &str) -> Result<&'static str, CliError> {
+    crate::opt::lookup(name)
+        .map(|pass| pass.name)
+        .ok_or_else(|| {
+            CliError(format!(
+                "未知的优化 `{name}`，应为 {} 之一",
+                crate::opt::names().join(" | ")
+            ))
+        })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CliError(pub String);
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, CliError> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn options() {
+        let options = parse(&["a.tl", "-o", "out.mlog", "--emit", "ir", "-"]).unwrap();
+        assert_eq!(options.inputs, ["a.tl", "-"]);
+        assert_eq!(options.output.as_deref(), Some("out.mlog"));
+        assert_eq!(options.emit, Emit::Ir);
+
+        assert_eq!(parse(&["--emit=ast", "a.tl"]).unwrap().emit, Emit::Ast);
+        assert_eq!(parse(&["a.tl", "-o", "-"]).unwrap().output, None);
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["a.tl", "-o"]).is_err());
+        assert!(parse(&["a.tl", "--emit", "asm"]).is_err());
+        assert!(parse(&["a.tl", "--what"]).is_err());
+
+        let options = parse(&["a.tl", "--no-pass", "dse", "--pass=dse", "--no-pass=thread"]);
+        assert_eq!(
+            options.unwrap().passes,
+            [("dse", false), ("dse", true), ("thread", false)]
+        );
+        assert!(parse(&["a.tl", "--no-pass", "inline"]).is_err());
+
+        use crate::opt::Level;
+        assert_eq!(parse(&["a.tl"]).unwrap().level, Level::Os);
+        assert_eq!(parse(&["-O2", "a.tl"]).unwrap().level, Level::O2);
+        assert_eq!(parse(&["a.tl", "-O0"]).unwrap().level, Level::O0);
+        assert!(parse(&["a.tl", "-O3"]).is_err());
+    }
+}
@@ -19,9 +19,20 @@ impl Error {
     }
 }
 
+impl Error {
+    /// 渲染为错误的诊断信息
+    pub fn diagnostic<'a>(&'a self, file: &'a str, lines: &'a [Vec<char>]) -> Diagnostic<'a> {
+        Diagnostic::new("error", &self.kind, self.location, &self.note, file, lines)
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self:?}")
+        if let Some(location) = self.location {
+            let (line, row) = location.display_location();
+            write!(f, "{line}:{row}: ")?;
+        }
+        write!(f, "{}", self.kind)
     }
 }
 
@@ -39,8 +50,58 @@ pub enum ErrorKind {
     /// 先前定义的位置
     DoubleFnDefine(Location),
     CallUnDefinedFn(String),
-    /// 定义的位置 函数的参数的个数 调用时传入的个数
-    CallFnWithIncorrectArgs(Location, usize, usize),
+    /// 定义的位置(内置函数没有) 函数的参数的个数 调用时传入的个数
+    CallFnWithIncorrectArgs(Option<Location>, usize, usize),
+    /// 定义了和内置函数同名的函数
+    RedefineIntrinsic(String),
+    /// 在函数之外使用了return
+    ReturnOutsideFn,
+    /// 在循环之外使用了break或continue
+    JumpOutsideLoop(String),
+    /// break或continue的标签不属于任何外层循环
+    UnDefinedLabel(String),
+    /// 直接或间接地递归调用了函数 被调用的函数
+    RecursiveCall(String),
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::None => write!(f, "语法错误"),
+            ErrorKind::CantAss => write!(f, "赋值运算符两边的元素数量不一致"),
+            ErrorKind::NotOneOf(of) => write!(f, "此处应为以下之一: {}", of.join(", ")),
+            ErrorKind::Not(be) => write!(f, "此处应为{be}"),
+            ErrorKind::UnExpect => write!(f, "意料之外的符号"),
+            ErrorKind::UnDefinedVar(name) => write!(f, "使用了未定义的变量 `{name}`"),
+            ErrorKind::DoubleFnDefine(location) => {
+                let (line, row) = location.display_location();
+                write!(f, "函数被重复定义，先前的定义位于 {line}:{row}")
+            }
+            ErrorKind::CallUnDefinedFn(name) => write!(f, "调用了未定义的函数 `{name}`"),
+            ErrorKind::ReturnOutsideFn => write!(f, "`return`只能在函数中使用"),
+            ErrorKind::JumpOutsideLoop(keyword) => write!(f, "`{keyword}`只能在循环中使用"),
+            ErrorKind::UnDefinedLabel(label) => write!(f, "未定义的标签 `{label}`"),
+            ErrorKind::RecursiveCall(name) => {
+                write!(
+                    f,
+                    "递归调用了函数 `{name}`，函数的参数和返回地址是静态分配的，不支持递归"
+                )
+            }
+            ErrorKind::CallFnWithIncorrectArgs(location, nr_args, nr_input) => {
+                write!(f, "函数需要{nr_args}个参数，调用时却传入了{nr_input}个")?;
+                match location {
+                    Some(location) => {
+                        let (line, row) = location.display_location();
+                        write!(f, " (函数定义于 {line}:{row})")
+                    }
+                    None => write!(f, " (内置函数)"),
+                }
+            }
+            ErrorKind::RedefineIntrinsic(name) => {
+                write!(f, "`{name}`是内置函数，不能被重新定义")
+            }
+        }
+    }
 }
 
 impl ErrorKind {
@@ -82,29 +143,196 @@ pub struct Warn {
     pub location: Option<Location>,
     pub note: String,
     pub kind: WarnKind,
+    /// 所在源文件的序号
+    pub source: usize,
 }
 
 impl Warn {
-    pub fn empty() -> Self {
-        Self {
-            location: None,
-            kind: WarnKind::None,
-            note: String::new(),
-        }
+    /// 渲染为警告的诊断信息
+    pub fn diagnostic<'a>(&'a self, file: &'a str, lines: &'a [Vec<char>]) -> Diagnostic<'a> {
+        Diagnostic::new(
+            "warning",
+            &self.kind,
+            self.location,
+            &self.note,
+            file,
+            lines,
+        )
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum WarnKind {
-    None,
+    /// 变量定义后从未被使用
+    UnusedVar(String),
+    /// 变量被赋值，但是从未被读取
+    NeverReadVar(String),
+    /// 函数定义后从未被调用
+    UnusedFn(String),
+    /// 遮蔽了外层作用域的变量 外层变量定义的位置
+    ShadowedVar(String, Location),
+    /// 之后的代码不可达
+    UnreachableCode,
+    /// 到drawflush之前，绘图缓冲区中的指令可能超过上限
+    DrawBufferOverflow,
+}
+
+impl Display for WarnKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WarnKind::UnusedVar(name) => write!(f, "变量 `{name}` 从未被使用"),
+            WarnKind::NeverReadVar(name) => write!(f, "变量 `{name}` 被赋值后从未被读取"),
+            WarnKind::UnusedFn(name) => write!(f, "函数 `{name}` 从未被调用"),
+            WarnKind::ShadowedVar(name, location) => {
+                let (line, row) = location.display_location();
+                write!(
+                    f,
+                    "变量 `{name}` 遮蔽了外层作用域中定义于 {line}:{row} 的同名变量"
+                )
+            }
+            WarnKind::UnreachableCode => write!(f, "此语句之后的代码永远不会被执行"),
+            WarnKind::DrawBufferOverflow => write!(
+                f,
+                "绘图缓冲区可能溢出，drawflush之前最多只能有{}条draw",
+                crate::syn::DRAW_BUFFER
+            ),
+        }
+    }
 }
 
 impl WarnKind {
-    pub fn is_none(&self) -> bool {
-        matches!(self, Self::None)
+    pub fn make_warn(self, location: Location) -> Warn {
+        Warn {
+            location: location.into(),
+            note: String::new(),
+            kind: self,
+            source: 0,
+        }
     }
+}
 
-    pub fn none() -> Self {
-        Self::None
+/// 渲染好的诊断信息
+///
+/// ```text
+/// error: 使用了未定义的变量 `y`
+///  --> test.tl:3:5
+///   |
+/// 3 |     y += 1
+///   |     ^
+/// ```
+pub struct Diagnostic<'a> {
+    level: &'static str,
+    message: String,
+    location: Option<Location>,
+    note: &'a str,
+    file: &'a str,
+    lines: &'a [Vec<char>],
+}
+
+impl<'a> Diagnostic<'a> {
+    /// 附上文件名和源码，渲染为带有源码片段的诊断信息
+    fn new(
+        level: &'static str,
+        kind: &dyn Display,
+        location: Option<Location>,
+        note: &'a str,
+        file: &'a str,
+        lines: &'a [Vec<char>],
+    ) -> Self {
+        Self {
+            level,
+            message: kind.to_string(),
+            location,
+            note,
+            file,
+            lines,
+        }
+    }
+}
+
+impl Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}: {}", self.level, self.message)?;
+
+        let Some(location) = self.location else {
+            writeln!(f, " --> {}", self.file)?;
+            return self.fmt_note(f, "");
+        };
+        let (line, row) = location.display_location();
+        let gutter = " ".repeat(line.to_string().len());
+        writeln!(f, "{gutter}--> {}:{line}:{row}", self.file)?;
+
+        if let Some(src) = self.lines.get(location.line) {
+            let src: String = src.iter().take_while(|&&c| c != '\n').collect();
+            // 跨行的token长度为0，至少标出一个字符
+            let marked = location
+                .locate(self.lines)
+                .map(|chars| {
+                    chars
+                        .iter()
+                        .take_while(|&&c| c != '\n')
+                        .map(|&c| width(c))
+                        .sum()
+                })
+                .unwrap_or(0)
+                .max(1);
+            let before: usize = self.lines[location.line]
+                .iter()
+                .take(location.row)
+                .map(|&c| width(c))
+                .sum();
+
+            writeln!(f, "{gutter} |")?;
+            writeln!(f, "{line} | {}", src.replace('\t', " "))?;
+            writeln!(f, "{gutter} | {}{}", " ".repeat(before), "^".repeat(marked))?;
+        }
+        self.fmt_note(f, &gutter)
+    }
+}
+
+impl Diagnostic<'_> {
+    fn fmt_note(&self, f: &mut std::fmt::Formatter<'_>, gutter: &str) -> std::fmt::Result {
+        let mut lines = self.note.lines().filter(|line| !line.trim().is_empty());
+        if let Some(first) = lines.next() {
+            writeln!(f, "{gutter} = note: {}", first.trim())?;
+            for line in lines {
+                writeln!(f, "{gutter}         {}", line.replace('\t', "    "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 字符在终端中的显示宽度，中文等全角字符占两格
+fn width(c: char) -> usize {
+    let wide = matches!(c as u32,
+        0x1100..=0x115F | 0x2E80..=0xA4CF | 0xAC00..=0xD7A3 | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F | 0xFF00..=0xFF60 | 0xFFE0..=0xFFE6);
+    if wide {
+        2
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostic() {
+        let lines = crate::lexer::split_lines("let x\n\ty += 1\n");
+        let mut error = ErrorKind::UnDefinedVar("y".to_owned()).make_error(Location::new(1, 1, 1));
+        error.note = "用法:\n\t第二行\n".to_owned();
+        assert_eq!(
+            error.diagnostic("test.tl", &lines).to_string(),
+            "error: 使用了未定义的变量 `y`\n \
+             --> test.tl:2:2\n  \
+             |\n\
+             2 |  y += 1\n  \
+             |  ^\n  \
+             = note: 用法:\n              \
+                   第二行\n"
+        );
     }
 }
@@ -0,0 +1,697 @@
+//! 内置函数
+//!
+//! 内置函数的调用和普通的函数调用语法相同，但是不会查找`GlobalSpace`中的函数，
+//! 而是直接翻译为对应的mlog指令
+
+use crate::{
+    abi::{
+        BuildingGroup, ControlCommand, DrawCommand, LookUp, Radar, RadarSort, Statement,
+        UnitControlCommand, UnitLocateKind, Variable, VariableName, VariableValue,
+    },
+    ast,
+    error::{Error, ErrorKind},
+    lexer::Location,
+    meta::GlobalSpace,
+    syn::{CompileUnit, Mark, Statements},
+};
+
+/// 内置函数的参数的种类
+#[derive(Debug, Clone, Copy)]
+pub enum ArgKind {
+    /// 任意表达式
+    Value,
+    /// 链接到处理器的建筑，比如`message1`
+    ///
+    /// 也可以是保存了建筑的变量
+    Building,
+    /// 写入结果的变量，没有定义时会被定义
+    Output,
+    /// sensor读取的属性，比如`@copper`和`@payloadCount`
+    ///
+    /// 也可以是保存了属性的变量
+    Attrib,
+    /// 关键字，比如radar的筛选条件 所有可能的关键字
+    Keyword(fn() -> Vec<&'static str>),
+    /// 子命令 所有可能的子命令 子命令之后的参数
+    SubCommand(fn() -> Vec<&'static str>, fn(&str) -> Vec<ArgKind>),
+}
+
+/// 已经生成的参数
+#[derive(Debug, Clone)]
+pub enum Arg {
+    Value(Variable),
+    /// 已经检查过的关键字
+    Keyword(&'static str),
+}
+
+/// 按顺序取出参数，参数的种类在调用`lower`之前已经检查过了
+pub struct Args(std::vec::IntoIter<Arg>);
+
+impl Args {
+    pub fn value(&mut self) -> Variable {
+        match self.0.next() {
+            Some(Arg::Value(value)) => value,
+            arg => unreachable!("参数应为值，而不是{arg:?}"),
+        }
+    }
+
+    pub fn keyword<T>(&mut self, from_name: fn(&str) -> Option<T>) -> T {
+        match self.0.next() {
+            Some(Arg::Keyword(name)) => from_name(name).unwrap(),
+            arg => unreachable!("参数应为关键字，而不是{arg:?}"),
+        }
+    }
+
+    /// 剩下的全部参数
+    pub fn values(&mut self) -> Vec<Variable> {
+        let mut values = vec![];
+        while !self.0.as_slice().is_empty() {
+            values.push(self.value());
+        }
+        values
+    }
+}
+
+#[derive(Debug)]
+pub struct Intrinsic {
+    pub name: &'static str,
+    pub args: &'static [ArgKind],
+    /// 最后一个参数可以重复任意次，但至少要有一个
+    pub variadic: bool,
+    /// 有返回值时，`lower`会得到一个分配的变量作为结果
+    pub returns: bool,
+    /// 参数 结果
+    lower: fn(&mut Args, Variable) -> Vec<Statement>,
+}
+
+const INTRINSICS: &[Intrinsic] = &[
+    Intrinsic {
+        name: "print",
+        args: &[ArgKind::Value],
+        variadic: true,
+        returns: false,
+        lower: |args, _| {
+            args.values()
+                .into_iter()
+                .map(|value| Statement::Print { value })
+                .collect()
+        },
+    },
+    Intrinsic {
+        name: "printflush",
+        args: &[ArgKind::Building],
+        variadic: false,
+        returns: false,
+        lower: |args, _| {
+            vec![Statement::PrintFlush {
+                message: args.value(),
+            }]
+        },
+    },
+    Intrinsic {
+        name: "draw",
+        args: &[ArgKind::SubCommand(DrawCommand::names, |name| {
+            vec![ArgKind::Value; DrawCommand::from_name(name).unwrap().nr_args()]
+        })],
+        variadic: false,
+        returns: false,
+        lower: |args, _| {
+            vec![Statement::Draw {
+                command: args.keyword(DrawCommand::from_name),
+                args: args.values(),
+            }]
+        },
+    },
+    Intrinsic {
+        name: "drawflush",
+        args: &[ArgKind::Building],
+        variadic: false,
+        returns: false,
+        lower: |args, _| {
+            vec![Statement::DrawFlush {
+                display: args.value(),
+            }]
+        },
+    },
+    Intrinsic {
+        name: "wait",
+        args: &[ArgKind::Value],
+        variadic: false,
+        returns: false,
+        lower: |args, _| vec![Statement::Wait { time: args.value() }],
+    },
+    Intrinsic {
+        name: "stop",
+        args: &[],
+        variadic: false,
+        returns: false,
+        lower: |_, _| vec![Statement::Stop],
+    },
+    Intrinsic {
+        name: "end",
+        args: &[],
+        variadic: false,
+        returns: false,
+        lower: |_, _| vec![Statement::End],
+    },
+    Intrinsic {
+        name: "sensor",
+        args: &[ArgKind::Building, ArgKind::Attrib],
+        variadic: false,
+        returns: true,
+        lower: |args, result| {
+            vec![Statement::Sensor {
+                result,
+                target: args.value(),
+                attrib: args.value(),
+            }]
+        },
+    },
+    Intrinsic {
+        name: "getlink",
+        args: &[ArgKind::Value],
+        variadic: false,
+        returns: true,
+        lower: |args, result| {
+            vec![Statement::GetLink {
+                result,
+                index: args.value(),
+            }]
+        },
+    },
+    Intrinsic {
+        name: "read",
+        args: &[ArgKind::Building, ArgKind::Value],
+        variadic: false,
+        returns: true,
+        lower: |args, result| {
+            vec![Statement::Read {
+                result,
+                cell: args.value(),
+                index: args.value(),
+            }]
+        },
+    },
+    Intrinsic {
+        name: "write",
+        args: &[ArgKind::Value, ArgKind::Building, ArgKind::Value],
+        variadic: false,
+        returns: false,
+        lower: |args, _| {
+            vec![Statement::Write {
+                value: args.value(),
+                cell: args.value(),
+                index: args.value(),
+            }]
+        },
+    },
+    Intrinsic {
+        name: "control",
+        args: &[ArgKind::SubCommand(ControlCommand::names, |name| {
+            let nr_args = ControlCommand::from_name(name).unwrap().nr_args();
+            let mut args = vec![ArgKind::Building];
+            args.resize(nr_args + 1, ArgKind::Value);
+            args
+        })],
+        variadic: false,
+        returns: false,
+        lower: |args, _| {
+            vec![Statement::Control {
+                command: args.keyword(ControlCommand::from_name),
+                block: args.value(),
+                args: args.values(),
+            }]
+        },
+    },
+    Intrinsic {
+        name: "radar",
+        args: &[
+            ArgKind::Building,
+            ArgKind::Keyword(Radar::names),
+            ArgKind::Keyword(Radar::names),
+            ArgKind::Keyword(Radar::names),
+            ArgKind::Keyword(RadarSort::names),
+            ArgKind::Value,
+        ],
+        variadic: false,
+        returns: true,
+        lower: |args, result| {
+            vec![Statement::Radar {
+                from: args.value(),
+                filters: [
+                    args.keyword(Radar::from_name),
+                    args.keyword(Radar::from_name),
+                    args.keyword(Radar::from_name),
+                ],
+                sort: args.keyword(RadarSort::from_name),
+                order: args.value(),
+                result,
+            }]
+        },
+    },
+    Intrinsic {
+        name: "packcolor",
+        args: &[ArgKind::Value; 4],
+        variadic: false,
+        returns: true,
+        lower: |args, result| {
+            let [r, g, b, a] = [args.value(), args.value(), args.value(), args.value()];
+            // 常量颜色直接折叠，和游戏一样，每个分量先限制在0到1之间
+            let channels = [&r, &g, &b, &a].map(|channel| channel.value.as_number().copied());
+            if let [Some(r), Some(g), Some(b), Some(a)] = channels {
+                let rgba = [r, g, b, a].into_iter().fold(0, |rgba, channel| {
+                    rgba << 8 | (channel.clamp(0.0, 1.0) * 255.0) as u32
+                });
+                let value = Variable::new(VariableName::None, VariableValue::Color(rgba));
+                return vec![Statement::set(result, value)];
+            }
+            vec![Statement::PackColor { result, r, g, b, a }]
+        },
+    },
+    // 种类的总数是`@itemCount`这样的内置变量
+    Intrinsic {
+        name: "lookup",
+        args: &[ArgKind::Keyword(LookUp::names), ArgKind::Value],
+        variadic: false,
+        returns: true,
+        lower: |args, result| {
+            vec![Statement::Lookup {
+                result,
+                kind: args.keyword(LookUp::from_name),
+                index: args.value(),
+            }]
+        },
+    },
+    // 单位控制
+    Intrinsic {
+        name: "ubind",
+        args: &[ArgKind::Value],
+        variadic: false,
+        returns: false,
+        lower: |args, _| {
+            vec![Statement::UnitBind {
+                unit_type: args.value(),
+            }]
+        },
+    },
+    Intrinsic {
+        name: "ucontrol",
+        args: &[ArgKind::SubCommand(
+            UnitControlCommand::names,
+            unit_control_args,
+        )],
+        variadic: false,
+        returns: false,
+        lower: |args, _| {
+            vec![Statement::UnitControl {
+                command: args.keyword(UnitControlCommand::from_name),
+                args: args.values(),
+            }]
+        },
+    },
+    Intrinsic {
+        name: "uradar",
+        args: &[
+            ArgKind::Keyword(Radar::names),
+            ArgKind::Keyword(Radar::names),
+            ArgKind::Keyword(Radar::names),
+            ArgKind::Keyword(RadarSort::names),
+            ArgKind::Value,
+        ],
+        variadic: false,
+        returns: true,
+        lower: |args, result| {
+            vec![Statement::UnitRadar {
+                filters: [
+                    args.keyword(Radar::from_name),
+                    args.keyword(Radar::from_name),
+                    args.keyword(Radar::from_name),
+                ],
+                sort: args.keyword(RadarSort::from_name),
+                order: args.value(),
+                result,
+            }]
+        },
+    },
+    Intrinsic {
+        name: "ulocate",
+        args: &[ArgKind::SubCommand(UnitLocateKind::names, unit_locate_args)],
+        variadic: false,
+        returns: false,
+        lower: |args, _| {
+            let kind = args.keyword(UnitLocateKind::from_name);
+            let (mut group, mut enemy, mut ore) = (
+                BuildingGroup::Core,
+                Variable::zero(),
+                Variable::new(VariableName::named("@copper"), VariableValue::MetaAttrib),
+            );
+            match kind {
+                UnitLocateKind::Ore => ore = args.value(),
+                UnitLocateKind::Building => {
+                    group = args.keyword(BuildingGroup::from_name);
+                    enemy = args.value();
+                }
+                UnitLocateKind::Spawn | UnitLocateKind::Damaged => {}
+            }
+            vec![Statement::UnitLocate {
+                kind,
+                group,
+                enemy,
+                ore,
+                out_x: args.value(),
+                out_y: args.value(),
+                found: args.value(),
+                // 找矿时没有建筑
+                building: match kind {
+                    UnitLocateKind::Ore => Variable::null(),
+                    _ => args.value(),
+                },
+            }]
+        },
+    },
+];
+
+/// ucontrol子命令的参数
+fn unit_control_args(name: &str) -> Vec<ArgKind> {
+    use ArgKind::*;
+    let command = UnitControlCommand::from_name(name).unwrap();
+    match command {
+        UnitControlCommand::ItemDrop => vec![Building, Value],
+        UnitControlCommand::ItemTake => vec![Building, Value, Value],
+        // 方块的种类 建筑 地板
+        UnitControlCommand::GetBlock => vec![Value, Value, Output, Output, Output],
+        UnitControlCommand::Within => vec![Value, Value, Value, Output],
+        _ => vec![Value; command.nr_args()],
+    }
+}
+
+/// ulocate的参数，结果依次为 x y 是否找到 建筑
+fn unit_locate_args(name: &str) -> Vec<ArgKind> {
+    use ArgKind::*;
+    match UnitLocateKind::from_name(name).unwrap() {
+        // 矿物
+        UnitLocateKind::Ore => vec![Value, Output, Output, Output],
+        // 建筑的类别 是否是敌方的
+        UnitLocateKind::Building => vec![
+            Keyword(BuildingGroup::names),
+            Value,
+            Output,
+            Output,
+            Output,
+            Output,
+        ],
+        UnitLocateKind::Spawn | UnitLocateKind::Damaged => vec![Output; 4],
+    }
+}
+
+/// 生成作为建筑的表达式
+///
+/// 未定义的名字是链接到处理器的建筑，直接使用其名字
+pub fn building(
+    global: &mut GlobalSpace,
+    stmts: &mut Statements,
+    expr: &mut ast::Expr,
+) -> Result<Variable, Error> {
+    if let ast::Expr::Var { token, name } = expr {
+        if global.global_lookup_var(name, token.location).is_err() {
+            return Ok(Variable::new(
+                VariableName::named(name),
+                VariableValue::MetaAttrib,
+            ));
+        }
+    }
+    expr.generate(global, stmts)?;
+    Ok(stmts.get_last_value().unwrap())
+}
+
+/// 生成作为属性的表达式
+///
+/// 属性的名字不一定是各种内容的总数，不像内置变量那样检查`@...Count`
+fn attrib(
+    global: &mut GlobalSpace,
+    stmts: &mut Statements,
+    expr: &mut ast::Expr,
+) -> Result<Variable, Error> {
+    if let ast::Expr::Var { name, .. } = expr {
+        if name.starts_with('@') {
+            return Ok(Variable::new(
+                VariableName::named(name),
+                VariableValue::MetaAttrib,
+            ));
+        }
+    }
+    expr.generate(global, stmts)?;
+    Ok(stmts.get_last_value().unwrap())
+}
+
+/// 作为输出的变量，没有定义时会在当前作用域定义
+fn output(global: &mut GlobalSpace, expr: &ast::Expr) -> Result<Variable, Error> {
+    let ast::Expr::Var { token, name } = expr else {
+        return Err(ErrorKind::not("变量").make_error(expr.location()));
+    };
+    if global.global_lookup_var(name, token.location).is_err() {
+        global.global_define_var(name, token.location);
+    }
+    global.global_ass_var(name, token.location)?;
+    Ok(Variable::new(
+        VariableName::named(&global.global_var_name(name)),
+        VariableValue::UnknowType,
+    ))
+}
+
+/// 关键字只能是名字
+fn keyword(names: fn() -> Vec<&'static str>, expr: &ast::Expr) -> Result<&'static str, Error> {
+    let names = names();
+    match expr {
+        ast::Expr::Var { name, .. } if names.contains(name) => {
+            Ok(names.into_iter().find(|this| this == name).unwrap())
+        }
+        _ => Err(ErrorKind::not_one_of(&names).make_error(expr.location())),
+    }
+}
+
+/// 查找内置函数
+pub fn lookup(name: &str) -> Option<&'static Intrinsic> {
+    INTRINSICS.iter().find(|intrinsic| intrinsic.name == name)
+}
+
+impl Intrinsic {
+    /// 检查参数的个数和关键字，得到全部参数的种类
+    fn signature(&self, location: Location, args: &[ast::Expr]) -> Result<Vec<ArgKind>, Error> {
+        let incorrect = |nr_args| {
+            ErrorKind::CallFnWithIncorrectArgs(None, nr_args, args.len()).make_error(location)
+        };
+
+        let mut kinds = self.args.to_vec();
+        let mut index = 0;
+        while index < kinds.len() {
+            if let ArgKind::SubCommand(names, expand) = kinds[index] {
+                let arg = args.get(index).ok_or_else(|| incorrect(kinds.len()))?;
+                let name = keyword(names, arg)?;
+                kinds.splice(index + 1..index + 1, expand(name));
+            }
+            index += 1;
+        }
+
+        if self.variadic && args.len() > kinds.len() {
+            let last = *kinds.last().unwrap();
+            kinds.resize(args.len(), last);
+        }
+        if kinds.len() != args.len() {
+            return Err(incorrect(kinds.len()));
+        }
+        Ok(kinds)
+    }
+
+    pub fn generate(
+        &self,
+        global: &mut GlobalSpace,
+        stmts: &mut Statements,
+        location: Location,
+        args: &mut [ast::Expr],
+    ) -> Result<(), Error> {
+        let kinds = self.signature(location, args)?;
+
+        let mut values = Vec::with_capacity(args.len());
+        for (kind, arg) in kinds.into_iter().zip(args.iter_mut()) {
+            let value = match kind {
+                ArgKind::Value => {
+                    arg.generate(global, stmts)?;
+                    Arg::Value(stmts.get_last_value().unwrap())
+                }
+                ArgKind::Building => Arg::Value(building(global, stmts, arg)?),
+                ArgKind::Output => Arg::Value(output(global, arg)?),
+                ArgKind::Attrib => Arg::Value(attrib(global, stmts, arg)?),
+                ArgKind::Keyword(names) | ArgKind::SubCommand(names, _) => {
+                    Arg::Keyword(keyword(names, arg)?)
+                }
+            };
+            values.push(value);
+        }
+
+        let result = if self.returns {
+            Variable::alloc()
+        } else {
+            Variable::null()
+        };
+        for stmt in (self.lower)(&mut Args(values.into_iter()), result) {
+            let is_draw = matches!(stmt, Statement::Draw { .. });
+            stmts.push_stmt(stmt);
+            if is_draw {
+                stmts.mark_last(Mark::Draw {
+                    location,
+                    source: global.source(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(src: &str) -> Result<String, Error> {
+        let cfg = crate::testing::generate(src, crate::opt::Level::O0)?;
+        Ok(crate::mlog::emit(&cfg.lower()))
+    }
+
+    #[test]
+    fn intrinsics() {
+        let src = "let x = read(cell1, 0)\nprint(\"x \", x)\nprintflush(message1)\nwait(0.5)\n";
+        assert_eq!(
+            compile(src).unwrap(),
+            "read x cell1 0\n\
+             print \"x \"\n\
+             print x\n\
+             printflush message1\n\
+             wait 0.5\n"
+        );
+
+        let src = "let a = container1.@copper\nlet b = sensor(reactor1, @heat) * 2\n";
+        assert_eq!(
+            compile(src).unwrap(),
+            "sensor a container1 @copper\n\
+             sensor __t0 reactor1 @heat\n\
+             op mul b __t0 2\n"
+        );
+        // 以Count结尾的属性不是各种内容的总数
+        let src = "let p = sensor(vault1, @payloadCount)\nlet q = vault1.@payloadCount\n";
+        assert_eq!(
+            compile(src).unwrap(),
+            "sensor p vault1 @payloadCount\n\
+             sensor q vault1 @payloadCount\n"
+        );
+
+        for (src, nr_args, nr_input) in [("print()\n", 1, 0), ("end(1)\n", 0, 1)] {
+            assert!(matches!(
+                compile(src).unwrap_err().kind,
+                ErrorKind::CallFnWithIncorrectArgs(None, a, i) if a == nr_args && i == nr_input
+            ));
+        }
+        assert!(matches!(
+            compile("fn wait(t) {\n}\n").unwrap_err().kind,
+            ErrorKind::RedefineIntrinsic(..)
+        ));
+    }
+
+    #[test]
+    fn unit_control() {
+        let src = "ubind(@poly)\n\
+                   let t = uradar(enemy, any, any, distance, 1)\n\
+                   ucontrol(itemTake, vault1, @copper, 30)\n\
+                   ucontrol(within, t.@x, 0, 5, near)\n\
+                   ulocate(ore, @titanium, x, y, found)\n";
+        assert_eq!(
+            compile(src).unwrap(),
+            "ubind @poly\n\
+             uradar enemy any any distance 0 1 t\n\
+             ucontrol itemTake vault1 @copper 30 0 0\n\
+             sensor __t0 t @x\n\
+             ucontrol within __t0 0 5 near 0\n\
+             ulocate ore core 0 @titanium x y found null\n"
+        );
+
+        let not_one_of = |src| matches!(compile(src).unwrap_err().kind, ErrorKind::NotOneOf(..));
+        assert!(not_one_of("ucontrol(mvoe, 1, 2)\n"));
+        assert!(not_one_of("ulocate(building, cores, 0, a, b, c, d)\n"));
+        assert!(matches!(
+            compile("ucontrol(move, 1)\n").unwrap_err().kind,
+            ErrorKind::CallFnWithIncorrectArgs(None, 3, 2)
+        ));
+        assert!(matches!(
+            compile("ucontrol()\n").unwrap_err().kind,
+            ErrorKind::CallFnWithIncorrectArgs(None, 1, 0)
+        ));
+    }
+
+    #[test]
+    fn control() {
+        let src = "control(enabled, door1, 0)\n\
+                   control(shoot, ripple1, 10, 20, 1)\n\
+                   control(color, illuminator1, %ff0000)\n";
+        assert_eq!(
+            compile(src).unwrap(),
+            "control enabled door1 0 0 0 0\n\
+             control shoot ripple1 10 20 1 0\n\
+             control color illuminator1 %ff0000ff 0 0 0\n"
+        );
+
+        assert!(matches!(
+            compile("control(enable, door1, 0)\n").unwrap_err().kind,
+            ErrorKind::NotOneOf(..)
+        ));
+        assert!(matches!(
+            compile("control(shootp, ripple1, u)\n").unwrap_err().kind,
+            ErrorKind::CallFnWithIncorrectArgs(None, 4, 3)
+        ));
+    }
+
+    #[test]
+    fn radar() {
+        let src = "let t = radar(turret1, enemy, flying, any, distance, 1)\n";
+        assert_eq!(
+            compile(src).unwrap(),
+            "radar enemy flying any distance turret1 1 t\n"
+        );
+
+        let src = "let t = radar(turret1, enemy, flyng, any, distance, 1)\n";
+        let error = compile(src).unwrap_err();
+        assert_eq!(error.location.unwrap().read_location(), (0, 30));
+        let ErrorKind::NotOneOf(of) = error.kind else {
+            panic!("{error:?}")
+        };
+        assert_eq!(of, Radar::names());
+    }
+
+    #[test]
+    fn lookup() {
+        let src = "for i in 0..@itemCount {\n    print(lookup(item, i))\n}\n";
+        let mlog = compile(src).unwrap();
+        assert!(mlog.contains("set __t0 @itemCount"), "{mlog}");
+        assert!(mlog.contains("greaterThanEq i __t0"), "{mlog}");
+        assert!(mlog.contains("lookup item __t1 i"), "{mlog}");
+        assert!(matches!(
+            compile("for i in 0..@itemsCount {}\n").unwrap_err().kind,
+            ErrorKind::NotOneOf(of) if of.contains(&"@liquidCount".to_owned())
+        ));
+        assert!(matches!(
+            compile("let x = lookup(items, 0)\n").unwrap_err().kind,
+            ErrorKind::NotOneOf(..)
+        ));
+    }
+
+    #[test]
+    fn packcolor() {
+        let src = "let a = packcolor(1, 0.5, 0, 1)\nlet b = %ff7f00\nlet c\nlet d = packcolor(c, 0, 0, 1)\nprint(a, b)\n";
+        assert_eq!(
+            compile(src).unwrap(),
+            "set a %ff7f00ff\n\
+             set b %ff7f00ff\n\
+             packcolor d c 0 0 1\n\
+             print a\n\
+             print b\n"
+        );
+    }
+}
@@ -7,12 +7,8 @@ pub struct Lexer {
 
 impl Lexer {
     pub fn new(src: &str) -> Lexer {
-        let lines = src
-            .lines()
-            .map(|l| l.chars().chain(std::iter::once('\n')).collect())
-            .collect();
         Lexer {
-            lines,
+            lines: split_lines(src),
             location: Location::new(0, 0, 0),
         }
     }
@@ -37,13 +33,47 @@ impl Lexer {
             .copied()
     }
 
+    /// 下一个字符，不会跨行
+    fn peek_char(&self) -> Option<char> {
+        self.lines
+            .get(self.location.line)?
+            .get(self.location.row + 1)
+            .copied()
+    }
+
+    /// 颜色字面量`%rrggbb`或者`%rrggbbaa`的十六进制部分
+    ///
+    /// 其他情况下`%`是取余运算符
+    fn color(&self) -> Option<String> {
+        let line = self.lines.get(self.location.line)?;
+        if line.get(self.location.row) != Some(&'%') {
+            return None;
+        }
+        let digits: String = line[self.location.row + 1..]
+            .iter()
+            .take_while(|c| c.is_alphanumeric() || **c == '_')
+            .collect();
+        let is_color =
+            matches!(digits.len(), 6 | 8) && digits.chars().all(|c| c.is_ascii_hexdigit());
+        is_color.then_some(digits)
+    }
+
     fn next(&mut self) -> Option<Token> {
         let this_char = self.this_char()?;
-        if this_char.is_alphabetic() || this_char == '_' || this_char == '@' {
+        // `'`开头的是循环的标签，比如`'outer`
+        let label = this_char == '\''
+            && self
+                .peek_char()
+                .is_some_and(|next| next.is_alphabetic() || next == '_');
+        if this_char.is_alphabetic() || this_char == '_' || this_char == '@' || label {
             self.collect(|s| {
                 let mut ident: String = String::new();
                 while let Some(this) = s.this_char() {
-                    if !(this.is_alphanumeric() || this == '_' || this_char == '@') {
+                    // `@`开头的名字中可以有`-`，比如`@phase-fabric`，但`-`之后必须是字母
+                    let meta_split = this_char == '@'
+                        && this == '-'
+                        && s.peek_char().is_some_and(|next| next.is_alphabetic());
+                    if !(ident.is_empty() || this.is_alphanumeric() || this == '_' || meta_split) {
                         break;
                     } else {
                         s.next_char();
@@ -57,12 +87,14 @@ impl Lexer {
                 let mut float = false;
                 let mut number: String = String::new();
                 while let Some(this) = s.this_char() {
-                    if !(this.is_ascii_digit()) || (!float && this == '.') {
+                    // 小数点后必须是数字，`0..n`中的`..`不属于数字
+                    let point = !float
+                        && this == '.'
+                        && s.peek_char().is_some_and(|next| next.is_ascii_digit());
+                    if !(this.is_ascii_digit() || point) {
                         break;
                     }
-                    if this == '.' {
-                        float = !float
-                    }
+                    float |= point;
                     s.next_char();
                     number.push(this)
                 }
@@ -73,11 +105,19 @@ impl Lexer {
                 s.next_char();
                 TokenVul::EndLine
             })
-        } else if this_char == ' ' {
-            while self.this_char()? == ' ' {
+        } else if matches!(this_char, ' ' | '\t' | '\r') {
+            while matches!(self.this_char()?, ' ' | '\t' | '\r') {
                 self.next_char();
             }
             self.next()
+        } else if let Some(color) = self.color() {
+            self.collect(|s| {
+                // 跳过`%`和十六进制数字
+                for _ in 0..=color.len() {
+                    s.next_char();
+                }
+                TokenVul::Color(parse_color(&color))
+            })
         } else if this_char == '#' {
             self.collect(|s| {
                 while s.this_char().is_some_and(|this| this != '\n') {
@@ -129,7 +169,10 @@ impl Lexer {
                 let mut string = String::from(this_char);
                 let mut symbol = match string.parse::<Symbol>() {
                     Ok(symbol) => symbol,
-                    Err(_) => return TokenVul::Unknow(string),
+                    Err(_) => {
+                        s.next_char();
+                        return TokenVul::Unknow(string);
+                    }
                 };
                 while let Some(char) = s.next_char() {
                     string.push(char);
@@ -148,10 +191,8 @@ impl Lexer {
         F: FnOnce(&mut Self) -> TokenVul,
     {
         let mut location = self.location;
+        // 无法识别的字符同样作为token，交给语法分析报错
         let vul = collector(self);
-        if let TokenVul::Unknow(..) = vul {
-            return None;
-        }
         if location.line == self.location.line {
             location.len = self.location.row - location.row;
         }
@@ -168,6 +209,23 @@ impl Lexer {
     }
 }
 
+/// 解析`rrggbb`或者`rrggbbaa`，省略的透明度为`ff`
+fn parse_color(digits: &str) -> u32 {
+    let rgba = u32::from_str_radix(digits, 16).unwrap();
+    if digits.len() == 6 {
+        rgba << 8 | 0xff
+    } else {
+        rgba
+    }
+}
+
+/// 按行切分源码，每行末尾保留一个`\n`，`Location`以此为准
+pub fn split_lines(src: &str) -> Vec<Vec<char>> {
+    src.lines()
+        .map(|l| l.chars().chain(std::iter::once('\n')).collect())
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct Token {
     pub location: Location,
@@ -205,7 +263,7 @@ impl Location {
     where
         S: Deref<Target = [char]>,
     {
-        Some(&lines.get(self.len)?[self.row..self.row + self.len])
+        lines.get(self.line)?.get(self.row..self.row + self.len)
     }
 }
 
@@ -214,6 +272,8 @@ pub enum TokenVul {
     Ident(String),
     String(String),
     Number(f64),
+    /// `%rrggbbaa`形式的颜色
+    Color(u32),
     Symbol(Symbol),
     EndLine,
     Unknow(String),
@@ -224,7 +284,8 @@ impl Display for TokenVul {
         match self {
             TokenVul::Ident(ident) => write!(f, "{ident}"),
             TokenVul::String(string) => write!(f, "\"{string}\""),
-            TokenVul::Number(number) => write!(f, "{number}"),
+            TokenVul::Number(number) => write!(f, "{}", crate::mlog::number(*number)),
+            TokenVul::Color(rgba) => write!(f, "{}", crate::mlog::color(*rgba)),
             TokenVul::Symbol(symbol) => write!(f, "{symbol}"),
             TokenVul::EndLine => write!(f, "ENDLINE"),
             TokenVul::Unknow(unknow) => write!(f, "UNKNOW({unknow})"),
@@ -325,17 +386,22 @@ symbols! {//     is_ass_op  is_op
     Shl      ,"<<"  ,false ,true  ,4;
     Shr      ,">>"  ,false ,true  ,4;
     Band     ,"&"   ,false ,true  ,4;
+    Bor      ,"|"   ,false ,true  ,4;
     Xor      ,"^"   ,false ,true  ,4;
     Flip     ,"~"   ,false ,true  ,4;
 
     // 词法符号
     Split    ,","   ,false ,false ,0;
+    Dot      ,"."   ,false ,false ,0;
+    Colon    ,":"   ,false ,false ,0;
+    Range    ,".."  ,false ,false ,0;
     BarcketL ,"("   ,false ,false ,0;
     BarcketR ,")"   ,false ,false ,0;
     SpaceL   ,"{"   ,false ,false ,0;
     SpaceR   ,"}"   ,false ,false ,0;
 
     // 赋值运算符（纯粹语法糖）
+    // `!` `<` `>` 没有复合赋值：`!=` `<=` `>=` 已经是比较运算符，写成赋值永远匹配不到
     Ass         ,"="    ,true  ,false ,0;
     AddAss      ,"+="   ,true  ,false ,0;
     SubAss      ,"-="   ,true  ,false ,0;
@@ -346,15 +412,13 @@ symbols! {//     is_ass_op  is_op
     PowAss      ,"**="  ,true  ,false ,0;
     NeqAss      ,"!=="  ,true  ,false ,0;
     AndAss      ,"&&="  ,true  ,false ,0;
-    NotAss      ,"!="   ,true  ,false ,0;
     OrAss       ,"||="  ,true  ,false ,0;
-    LrAss       ,"<="   ,true  ,false ,0;
-    GrAss       ,">="   ,true  ,false ,0;
     LrEAss      ,"<=="  ,true  ,false ,0;
     GrEAss      ,">=="  ,true  ,false ,0;
     ShlAss      ,"<<="  ,true  ,false ,0;
     ShrAss      ,">>="  ,true  ,false ,0;
     BandAss     ,"&="   ,true  ,false ,0;
+    BorAss      ,"|="   ,true  ,false ,0;
     XorAss      ,"^="   ,true  ,false ,0;
     FlipAss     ,"~="   ,true  ,false ,0
 
@@ -379,15 +443,13 @@ impl Symbol {
             Self::PowAss => Self::Pow,
             Self::NeqAss => Self::Neq,
             Self::AndAss => Self::And,
-            Self::NotAss => Self::Not,
             Self::OrAss => Self::Or,
-            Self::LrAss => Self::Lr,
-            Self::GrAss => Self::Gr,
             Self::LrEAss => Self::LrE,
             Self::GrEAss => Self::GrE,
             Self::ShlAss => Self::Shl,
             Self::ShrAss => Self::Shr,
             Self::BandAss => Self::Band,
+            Self::BorAss => Self::Bor,
             Self::XorAss => Self::Xor,
             Self::FlipAss => Self::Flip,
             _ => return None,
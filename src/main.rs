@@ -1,10 +1,22 @@
 mod abi;
 mod ast;
+mod cfg;
+mod cli;
 mod error;
+mod intrinsic;
 mod lexer;
 mod meta;
+mod mlog;
+mod opt;
 mod parser;
 mod syn;
+#[cfg(test)]
+mod testing;
+
+use std::{fmt::Write, io::Read, process::ExitCode};
+
+use cli::Emit;
+
 /*
     编译流程：
         源码 经过词法分析 被解析成大量Token
@@ -14,26 +26,196 @@ mod syn;
             * 参数的作用域静态检查
 
 */
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // use std::env::args;
-    // let mut args = args();
-    // assert!(args.len() == 2, "用法: <输入文件>");
-    // let src_path = args.nth(1).unwrap();
-    let src_path = "test.tl";
-    let src = std::fs::read_to_string(src_path)?;
-    let tokens = lexer::Lexer::new(&src).toekns();
-    let mut parser = parser::Parser::new(&tokens);
-    let cus = parser.get_compile_units()?;
-    dbg!(&cus);
+fn main() -> ExitCode {
+    let options = match cli::Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {e}\n\n{}", cli::USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    if options.help {
+        println!("{}", cli::USAGE);
+        return ExitCode::SUCCESS;
+    }
+
+    let Ok(output) = compile(&options) else {
+        return ExitCode::FAILURE;
+    };
+
+    match &options.output {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, output) {
+                eprintln!("error: 无法写入 `{path}`: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        None => print!("{output}"),
+    }
+    ExitCode::SUCCESS
+}
+
+struct Source {
+    name: String,
+    src: String,
+    lines: Vec<Vec<char>>,
+}
+
+impl Source {
+    fn read(path: &str) -> std::io::Result<Self> {
+        if path == "-" {
+            let mut src = String::new();
+            std::io::stdin().read_to_string(&mut src)?;
+            Ok(Self::new("<stdin>".to_owned(), src))
+        } else {
+            Ok(Self::new(path.to_owned(), std::fs::read_to_string(path)?))
+        }
+    }
+
+    fn new(name: String, src: String) -> Self {
+        let lines = lexer::split_lines(&src);
+        Self { name, src, lines }
+    }
+
+    fn report(&self, error: &error::Error) {
+        eprintln!("{}", error.diagnostic(&self.name, &self.lines));
+    }
+}
+
+/// 按照`options.emit`编译到对应的阶段，出错时已经输出了诊断信息
+fn compile(options: &cli::Options) -> Result<String, ()> {
+    let mut sources = Vec::with_capacity(options.inputs.len());
+    for input in &options.inputs {
+        match Source::read(input) {
+            Ok(source) => sources.push(source),
+            Err(e) => {
+                eprintln!("error: 无法读取 `{input}`: {e}");
+                return Err(());
+            }
+        }
+    }
+
+    // Ast借用了tokens，需要一直保留到编译结束
+    let tokens: Vec<Vec<lexer::Token>> = sources
+        .iter()
+        .map(|source| lexer::Lexer::new(&source.src).toekns())
+        .collect();
+
+    let mut buffer = String::new();
+    if options.emit == Emit::Tokens {
+        for (source, tokens) in sources.iter().zip(&tokens) {
+            let _ = writeln!(buffer, "# {}", source.name);
+            for token in tokens {
+                let _ = writeln!(buffer, "{token}");
+            }
+        }
+        return Ok(buffer);
+    }
+
+    let mut units = Vec::with_capacity(sources.len());
+    let mut failed = false;
+    for (source, tokens) in sources.iter().zip(&tokens) {
+        let mut parser = parser::Parser::new(tokens);
+        let (cus, errors) = parser.get_compile_units();
+        for e in &errors {
+            source.report(e);
+        }
+        failed |= !errors.is_empty();
+        units.push((source, cus));
+    }
+    if failed {
+        return Err(());
+    }
+
+    if options.emit == Emit::Ast {
+        for (source, cus) in &units {
+            let _ = writeln!(buffer, "# {}", source.name);
+            for cu in cus {
+                let cu = cu.to_string();
+                if !cu.is_empty() {
+                    let _ = writeln!(buffer, "{cu}");
+                }
+            }
+        }
+        return Ok(buffer);
+    }
 
     let mut global = meta::GlobalSpace::new();
-    let mut stmts = syn::Statements::new();
+    global.set_level(options.level);
+    let units = units.into_iter().map(|(_, cus)| cus).collect();
+    let (stmts, mut warns) = generate(units, &mut global);
+    let report_warns = |warns: Vec<error::Warn>| {
+        for warn in warns {
+            let source = &sources[warn.source];
+            eprintln!("{}", warn.diagnostic(&source.name, &source.lines));
+        }
+    };
+    let stmts = match stmts {
+        Ok(stmts) => stmts,
+        Err((index, e)) => {
+            report_warns(warns);
+            sources[index].report(&e);
+            return Err(());
+        }
+    };
+    let mut passes = opt::PassManager::new(options.level);
+    for &(pass, enable) in &options.passes {
+        passes.set(pass, enable);
+    }
+    let mut cfg = cfg::Cfg::new(stmts);
 
-    for mut cu in cus {
-        stmts.link(|stmts| cu.generate(&mut global, stmts))?;
+    // 中间代码是代码生成的直接结果，用来对照优化前后的差别
+    if options.emit == Emit::Ir {
+        report_warns(warns);
+        return Ok(mlog::emit_ir(&cfg));
     }
 
-    dbg!(&stmts);
+    passes.run(&mut cfg);
+    let program = cfg.lower();
+    warns.extend(program.check_draw_buffer());
+    report_warns(warns);
+    Ok(mlog::emit(&program))
+}
 
-    Ok(())
+/// 生成所有源文件的中间代码，返回出错的源文件序号和错误
+///
+/// 不论是否出错都会带回已经收集到的警告
+fn generate(
+    units: Vec<Vec<Box<dyn syn::CompileUnit>>>,
+    global: &mut meta::GlobalSpace,
+) -> (
+    Result<syn::Statements, (usize, error::Error)>,
+    Vec<error::Warn>,
+) {
+    let mut stmts = syn::Statements::new();
+    for (index, mut cus) in units.into_iter().enumerate() {
+        global.set_source(index);
+        if let Err(e) = syn::generate_compile_units(&mut cus, global, &mut stmts) {
+            return (Err((index, e)), global.take_warns());
+        }
+    }
+    stmts.link_fns();
+    (Ok(stmts), global.take_warns())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_with_error() {
+        let srcs = ["let unused = 1\n", "let x = 1\nprint(x)\nundefined()\n"];
+        let units = srcs.iter().map(|src| testing::parse(src)).collect();
+        let mut global = meta::GlobalSpace::new();
+        let (stmts, warns) = generate(units, &mut global);
+
+        let Err((index, e)) = stmts else {
+            panic!("调用未定义的函数应该出错");
+        };
+        assert_eq!(index, 1);
+        assert!(matches!(e.kind, error::ErrorKind::CallUnDefinedFn(_)));
+        assert_eq!(warns.len(), 1);
+        assert_eq!(warns[0].source, 0);
+        assert!(matches!(warns[0].kind, error::WarnKind::UnusedVar(_)));
+    }
 }
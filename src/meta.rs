@@ -1,16 +1,42 @@
 use std::{collections::HashMap, fmt::Debug};
 
 use crate::{
-    abi::VariableValue,
-    error::{Error, ErrorKind},
+    cfg::Label,
+    error::{Error, ErrorKind, Warn, WarnKind},
     lexer::Location,
+    opt::Level,
+    syn::Statements,
 };
 
 #[derive(Debug, Clone)]
 pub struct GlobalSpace {
     spaces: Vec<Space>,
-    mapping: HashMap<usize, usize>,
     this_space: usize,
+    /// 正在编译的源文件的序号，用来标记警告的来源
+    source: usize,
+    warns: Vec<Warn>,
+    /// 刚刚生成的语句之后的代码是否不可达，值为导致不可达的语句的位置
+    diverged: Option<Location>,
+    /// 正在编译的函数，用于return
+    this_fn: Option<FnDefine>,
+    /// 正在编译的循环，由外到内，用于break和continue
+    loops: Vec<Loop>,
+    level: Level,
+    /// 可以在调用处展开的函数体 函数的编号
+    inlines: HashMap<usize, Statements>,
+    /// 函数调用图 调用者的编号 被调用的函数的编号
+    calls: HashMap<usize, Vec<usize>>,
+}
+
+/// 循环的标签和跳转目标，以及所在的函数
+#[derive(Debug, Clone)]
+struct Loop {
+    label: Option<String>,
+    r#fn: Option<usize>,
+    r#break: Label,
+    r#continue: Label,
+    /// 循环中是否有break
+    broken: bool,
 }
 
 impl GlobalSpace {
@@ -18,10 +44,205 @@ impl GlobalSpace {
         Self {
             spaces: vec![Space::new(0)],
             this_space: 0,
-            mapping: HashMap::new(),
+            source: 0,
+            warns: vec![],
+            diverged: None,
+            this_fn: None,
+            loops: vec![],
+            level: Level::default(),
+            inlines: HashMap::new(),
+            calls: HashMap::new(),
         }
     }
 
+    pub fn set_level(&mut self, level: Level) {
+        self.level = level;
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// 记录可以展开的函数体，应为`Statements::inlined`的结果
+    pub fn define_inline(&mut self, id: usize, body: Statements) {
+        self.inlines.insert(id, body);
+    }
+
+    pub fn inline_body(&self, id: usize) -> Option<&Statements> {
+        self.inlines.get(&id)
+    }
+
+    pub fn set_source(&mut self, source: usize) {
+        self.source = source;
+        self.diverged = None;
+    }
+
+    pub fn source(&self) -> usize {
+        self.source
+    }
+
+    pub fn warn(&mut self, mut warn: Warn) {
+        warn.source = self.source;
+        self.warns.push(warn);
+    }
+
+    /// 标记刚刚生成的语句之后的代码不可达
+    pub fn diverge(&mut self, location: Location) {
+        self.diverged = Some(location);
+    }
+
+    pub fn take_diverged(&mut self) -> Option<Location> {
+        self.diverged.take()
+    }
+
+    /// 进入函数体，返回之前正在编译的函数
+    pub fn enter_fn(&mut self, define: FnDefine) -> Option<FnDefine> {
+        self.this_fn.replace(define)
+    }
+
+    /// 离开函数体，恢复`enter_fn`返回的函数
+    pub fn leave_fn(&mut self, outer: Option<FnDefine>) {
+        self.this_fn = outer;
+    }
+
+    pub fn this_fn(&self) -> Option<&FnDefine> {
+        self.this_fn.as_ref()
+    }
+
+    /// 进入循环体，break和continue分别跳转到`r#break`和`r#continue`
+    pub fn enter_loop(&mut self, label: Option<&str>, r#break: Label, r#continue: Label) {
+        self.loops.push(Loop {
+            label: label.map(str::to_owned),
+            r#fn: self.this_fn.as_ref().map(|define| define.id),
+            r#break,
+            r#continue,
+            broken: false,
+        });
+    }
+
+    /// 离开循环体，返回循环中是否有break
+    pub fn leave_loop(&mut self) -> bool {
+        self.loops.pop().is_some_and(|r#loop| r#loop.broken)
+    }
+
+    /// 查找break或continue的跳转目标，没有标签时是最内层的循环
+    ///
+    /// 函数体中看不到调用处外层的循环
+    pub fn find_loop(&mut self, label: Option<&str>, r#break: bool) -> Option<Label> {
+        let this_fn = self.this_fn.as_ref().map(|define| define.id);
+        let r#loop = self
+            .loops
+            .iter_mut()
+            .rev()
+            .take_while(|r#loop| r#loop.r#fn == this_fn)
+            .find(|r#loop| label.is_none() || r#loop.label.as_deref() == label)?;
+        if r#break {
+            r#loop.broken = true;
+            Some(r#loop.r#break)
+        } else {
+            Some(r#loop.r#continue)
+        }
+    }
+
+    /// 在当前作用域定义变量，如果遮蔽了外层作用域的同名变量会给出警告
+    ///
+    /// 函数中定义的变量和参数一样，在mlog中以函数名为前缀
+    pub fn global_define_var(&mut self, name: &str, location: Location) {
+        let alias = self.this_fn.as_ref().map(|define| define.local(name));
+        let mut space = self.this_space;
+        while space != 0 {
+            space = self[space].super_space;
+            if let Some(record) = self[space].vars.get(name) {
+                // 在mlog中名字不同时不会冲突
+                if record.alias != alias {
+                    break;
+                }
+                let mut warn =
+                    WarnKind::ShadowedVar(name.to_owned(), record.defines[0]).make_warn(location);
+                warn.note = "mlog中没有作用域，两者实际上是同一个变量".to_owned();
+                self.warn(warn);
+                break;
+            }
+        }
+        let source = self.source;
+        self.define_var(name, location);
+        let record = self.vars.get_mut(name).unwrap();
+        record.alias = alias;
+        record.source = source;
+    }
+
+    /// 在当前作用域定义函数参数，参数在mlog中使用`alias`作为名字
+    pub fn define_parm(&mut self, name: &str, alias: &str, location: Location) {
+        let source = self.source;
+        self.define_var(name, location);
+        let record = self.vars.get_mut(name).unwrap();
+        record.alias = Some(alias.to_owned());
+        record.source = source;
+    }
+
+    /// 在当前作用域定义函数
+    pub fn global_define_fn(
+        &mut self,
+        name: &str,
+        location: Location,
+        parms: &[&str],
+    ) -> Result<FnDefine, Error> {
+        let source = self.source;
+        let define = self.define_fn(name, location, parms)?;
+        self.fns.get_mut(name).unwrap().source = source;
+        Ok(define)
+    }
+
+    /// 变量在mlog中的名字
+    pub fn global_var_name(&self, name: &str) -> String {
+        let mut space = self.this_space;
+        loop {
+            if let Some(record) = self[space].vars.get(name) {
+                return record.alias.clone().unwrap_or_else(|| name.to_owned());
+            }
+            if space == 0 {
+                return name.to_owned();
+            }
+            space = self[space].super_space;
+        }
+    }
+
+    /// 编译结束后，根据变量和函数的使用记录给出剩余的警告
+    pub fn take_warns(&mut self) -> Vec<Warn> {
+        let mut warns = std::mem::take(&mut self.warns);
+        for space in &self.spaces {
+            for (name, record) in &space.vars {
+                // 和rust一样，以`_`开头的变量不检查
+                if name.starts_with('_') || !record.uses.is_empty() {
+                    continue;
+                }
+                // 初始化时赋值的位置就是定义的位置
+                let reassigned = record
+                    .asses
+                    .iter()
+                    .any(|location| !record.defines.contains(location));
+                let kind = if reassigned {
+                    WarnKind::NeverReadVar(name.clone())
+                } else {
+                    WarnKind::UnusedVar(name.clone())
+                };
+                let mut warn = kind.make_warn(record.defines[0]);
+                warn.source = record.source;
+                warns.push(warn);
+            }
+            for (name, record) in &space.fns {
+                if name.starts_with('_') || !record.uses.is_empty() {
+                    continue;
+                }
+                let mut warn = WarnKind::UnusedFn(name.clone()).make_warn(record.define.location);
+                warn.source = record.source;
+                warns.push(warn);
+            }
+        }
+        warns.sort_by_key(|warn| (warn.source, warn.location));
+        warns
+    }
+
     pub fn new_space(&mut self) -> usize {
         let space = Space::new(self.this_space);
         self.this_space = self.spaces.len();
@@ -34,16 +255,13 @@ impl GlobalSpace {
         self.this_space
     }
 
-    pub fn global_lookup_var(
-        &mut self,
-        name: &str,
-        location: Location,
-    ) -> Result<&VariableValue, Error> {
+    /// 检查变量是否已经定义
+    pub fn global_lookup_var(&mut self, name: &str, location: Location) -> Result<(), Error> {
         let mut space = self.this_space;
 
         loop {
-            if let Some(value) = self[space].local_lookup_var(name, location) {
-                return Ok(value);
+            if self[space].local_lookup_var(name, location).is_some() {
+                return Ok(());
             }
             if space == 0 {
                 return Err(ErrorKind::UnDefinedVar(name.to_owned()).make_error(location));
@@ -67,18 +285,11 @@ impl GlobalSpace {
         }
     }
 
-    pub fn global_ass_var(
-        &mut self,
-        name: &str,
-        location: Location,
-        val: VariableValue,
-    ) -> Result<(), Error> {
+    pub fn global_ass_var(&mut self, name: &str, location: Location) -> Result<(), Error> {
         let mut space = self.this_space;
-        let mut val = Some(val);
         loop {
-            match self[space].local_ass_var(name, val.take().unwrap()) {
-                Ok(..) => return Ok(()),
-                Err(v) => val = Some(v),
+            if self[space].local_ass_var(name, location).is_some() {
+                return Ok(());
             }
             if space == 0 {
                 return Err(ErrorKind::UnDefinedVar(name.to_string()).make_error(location));
@@ -93,12 +304,12 @@ impl GlobalSpace {
         name: &str,
         location: Location,
         nr_args: usize,
-    ) -> Result<(), Error> {
+    ) -> Result<FnDefine, Error> {
         let mut space = self.this_space;
 
-        loop {
+        let define = loop {
             match self[space].local_use_fn(name, location, nr_args) {
-                Some(result) => return result,
+                Some(result) => break result?,
                 None => {
                     if space == 0 {
                         return Err(
@@ -109,21 +320,34 @@ impl GlobalSpace {
                     }
                 }
             };
+        };
+
+        // 函数只能调用已经定义的函数，所以只有调用外层正在编译的函数时才会成环
+        if let Some(caller) = self.this_fn.as_ref().map(|define| define.id) {
+            if self.reaches(define.id, caller) {
+                return Err(ErrorKind::RecursiveCall(name.to_owned()).make_error(location));
+            }
+            self.calls.entry(caller).or_default().push(define.id);
         }
+        Ok(define)
     }
 
-    pub fn in_space<F, R>(&mut self, index: usize, active: F) -> Option<R>
-    where
-        F: FnOnce(&mut Self) -> R,
-    {
-        if index >= self.spaces.len() {
-            return None;
+    /// 在调用图中，函数`from`是否会（间接地）调用到函数`to`
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut visited = vec![from];
+        let mut stack = vec![from];
+        while let Some(id) = stack.pop() {
+            if id == to {
+                return true;
+            }
+            for &callee in self.calls.get(&id).into_iter().flatten() {
+                if !visited.contains(&callee) {
+                    visited.push(callee);
+                    stack.push(callee);
+                }
+            }
         }
-        let backup = self.this_space;
-        self.this_space = index;
-        let r = active(self);
-        self.this_space = backup;
-        Some(r)
+        false
     }
 }
 
@@ -172,19 +396,13 @@ impl Space {
         }
     }
 
-    pub fn local_lookup_var(&self, name: &str, location: Location) -> Option<&VariableValue> {
-        let var = self.vars.get(name)?;
-        var.vul.as_ref()
+    pub fn local_lookup_var(&self, name: &str, _location: Location) -> Option<&VarRecords> {
+        self.vars.get(name)
     }
 
-    pub fn local_ass_var(&mut self, name: &str, val: VariableValue) -> Result<(), VariableValue> {
-        match self.vars.get_mut(name) {
-            Some(v) => {
-                v.vul = Some(val);
-                Ok(())
-            }
-            None => Err(val),
-        }
+    pub fn local_ass_var(&mut self, name: &str, location: Location) -> Option<()> {
+        self.vars.get_mut(name)?.asses.push(location);
+        Some(())
     }
 
     fn local_use_var(&mut self, name: &str, location: Location) -> Option<()> {
@@ -193,11 +411,13 @@ impl Space {
     }
 
     pub fn define_var(&mut self, name: &str, location: Location) {
-        self.vars
+        let record = self
+            .vars
             .entry(name.to_owned())
-            .or_insert_with(VarRecords::new)
-            .defines
-            .push(location);
+            .or_insert_with(VarRecords::new);
+        record.defines.push(location);
+        // 重新定义的变量不再是参数
+        record.alias = None;
     }
 
     pub fn local_use_fn(
@@ -205,18 +425,18 @@ impl Space {
         name: &str,
         location: Location,
         nr_args: usize,
-    ) -> Option<Result<(), Error>> {
+    ) -> Option<Result<FnDefine, Error>> {
         let fn_record = self.fns.get_mut(name)?;
         if fn_record.define.nr_args != nr_args {
             Some(Err(ErrorKind::CallFnWithIncorrectArgs(
-                fn_record.define.location,
+                Some(fn_record.define.location),
                 fn_record.define.nr_args,
                 nr_args,
             )
             .make_error(location)))
         } else {
             fn_record.uses.push(location);
-            Some(Ok(()))
+            Some(Ok(fn_record.define.clone()))
         }
     }
 
@@ -224,19 +444,20 @@ impl Space {
         &mut self,
         name: &str,
         location: Location,
-        nr_args: usize,
-    ) -> Result<(), Error> {
+        parms: &[&str],
+    ) -> Result<FnDefine, Error> {
         let entity = self.fns.get(name);
         match &entity {
             Some(record) => {
-                return Err(ErrorKind::DoubleFnDefine(record.define.location).make_error(location))
+                Err(ErrorKind::DoubleFnDefine(record.define.location).make_error(location))
             }
-            None => self.fns.insert(
-                name.to_owned(),
-                FnRecords::new(FnDefine { nr_args, location }),
-            ),
-        };
-        Ok(())
+            None => {
+                let define = FnDefine::new(name, location, parms);
+                self.fns
+                    .insert(name.to_owned(), FnRecords::new(define.clone()));
+                Ok(define)
+            }
+        }
     }
 }
 
@@ -244,8 +465,12 @@ impl Space {
 pub struct VarRecords {
     pub defines: Vec<Location>,
     pub uses: Vec<Location>,
+    pub asses: Vec<Location>,
     // r#type : abi::Type
-    vul: Option<VariableValue>,
+    /// 定义所在的源文件
+    source: usize,
+    /// 在mlog中使用的名字，用于函数参数
+    alias: Option<String>,
 }
 
 impl VarRecords {
@@ -253,7 +478,9 @@ impl VarRecords {
         Self {
             defines: vec![],
             uses: vec![],
-            vul: None,
+            asses: vec![],
+            source: 0,
+            alias: None,
         }
     }
 }
@@ -262,12 +489,54 @@ impl VarRecords {
 pub struct FnRecords {
     define: FnDefine,
     uses: Vec<Location>,
+    /// 定义所在的源文件
+    source: usize,
 }
 
 #[derive(Debug, Clone)]
 pub struct FnDefine {
-    nr_args: usize,
-    location: Location,
+    /// 全局唯一的编号
+    pub id: usize,
+    /// 函数体的入口
+    pub entry: Label,
+    pub name: String,
+    pub nr_args: usize,
+    pub location: Location,
+    /// 参数在mlog中的名字
+    pub parms: Vec<String>,
+}
+
+/// 函数使用静态的"栈帧"：参数、局部变量、返回值、返回地址都是以函数名为前缀的全局变量
+///
+/// 因此不支持递归
+impl FnDefine {
+    pub fn new(name: &str, location: Location, parms: &[&str]) -> Self {
+        use std::sync::atomic;
+        static FN_INDEX: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+        Self {
+            id: FN_INDEX.fetch_add(1, atomic::Ordering::Relaxed),
+            entry: Label::alloc(),
+            name: name.to_owned(),
+            nr_args: parms.len(),
+            location,
+            parms: parms.iter().map(|parm| format!("{name}.{parm}")).collect(),
+        }
+    }
+
+    /// 函数中定义的变量在mlog中的名字
+    pub fn local(&self, name: &str) -> String {
+        format!("{}.{name}", self.name)
+    }
+
+    /// 返回值在mlog中的名字
+    pub fn ret(&self) -> String {
+        format!("{}.ret", self.name)
+    }
+
+    /// 返回地址在mlog中的名字
+    pub fn addr(&self) -> String {
+        format!("{}.addr", self.name)
+    }
 }
 
 impl FnRecords {
@@ -275,6 +544,45 @@ impl FnRecords {
         Self {
             define,
             uses: vec![],
+            source: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lints() {
+        let at = |line| Location::new(line, 0, 1);
+        let mut global = GlobalSpace::new();
+        global.global_define_var("unused", at(0));
+        global.global_define_var("_unused", at(1));
+        global.global_define_var("written", at(2));
+        global.global_ass_var("written", at(3)).unwrap();
+        global.global_define_var("read", at(4));
+        global.new_space();
+        global.global_define_var("read", at(5));
+        global.global_use_var("read", at(6));
+        global.close_space();
+        global.global_use_var("read", at(7));
+
+        let warns: Vec<_> = global
+            .take_warns()
+            .into_iter()
+            .map(|warn| (warn.location.unwrap().line, warn.kind.to_string()))
+            .collect();
+        assert_eq!(
+            warns,
+            [
+                (0, WarnKind::UnusedVar("unused".to_owned()).to_string()),
+                (2, WarnKind::NeverReadVar("written".to_owned()).to_string()),
+                (
+                    5,
+                    WarnKind::ShadowedVar("read".to_owned(), at(4)).to_string()
+                ),
+            ]
+        );
+    }
+}
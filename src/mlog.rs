@@ -0,0 +1,447 @@
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{
+    abi::{JumpCondition, Statement, Variable, VariableName, VariableValue},
+    cfg::{Cfg, Exit, Program},
+    lexer::Symbol,
+};
+
+/// 把`Program`翻译为可以直接粘贴进处理器的mlog文本
+pub fn emit(stmts: &Program) -> String {
+    let mut emitter = Emitter::new();
+    let mut buffer = String::new();
+    for stmt in stmts.iter() {
+        let _ = writeln!(buffer, "{}", emitter.statement(stmt));
+    }
+    buffer
+}
+
+/// 按照布局输出控制流图，每个块以标签开头
+///
+/// 语句的格式和mlog相同，出口中的跳转目标是标签
+pub fn emit_ir(cfg: &Cfg) -> String {
+    let mut emitter = Emitter::new();
+    let mut buffer = String::new();
+    for block in cfg.blocks() {
+        let _ = writeln!(buffer, "{}:", block.label);
+        for stmt in &block.stmts {
+            let _ = writeln!(buffer, "    {}", emitter.statement(stmt));
+        }
+        for line in emitter.exit(&block.exit) {
+            let _ = writeln!(buffer, "    {line}");
+        }
+    }
+    buffer
+}
+
+/// 负责给临时变量分配稳定的名字
+///
+/// `VariableName::Index`来自全局计数器，数值取决于编译顺序，
+/// 这里按照第一次出现的顺序重新编号
+struct Emitter {
+    indexs: HashMap<usize, usize>,
+}
+
+impl Emitter {
+    fn new() -> Self {
+        Self {
+            indexs: HashMap::new(),
+        }
+    }
+
+    fn statement(&mut self, stmt: &Statement) -> String {
+        match stmt {
+            Statement::Set { result, value } => {
+                format!("set {} {}", self.name(&result.name), self.operand(value))
+            }
+            Statement::Operation { result, op, v1, v2 } => format!(
+                "op {} {} {} {}",
+                op_name(*op),
+                self.name(&result.name),
+                self.operand(v1),
+                self.operand(v2)
+            ),
+            Statement::Jump {
+                left,
+                cond,
+                right,
+                target,
+            } => format!(
+                "jump {} {} {} {}",
+                target,
+                condition_name(cond),
+                self.operand(left),
+                self.operand(right)
+            ),
+            Statement::Read {
+                result,
+                cell,
+                index,
+            } => format!(
+                "read {} {} {}",
+                self.name(&result.name),
+                self.operand(cell),
+                self.operand(index)
+            ),
+            Statement::Write { value, cell, index } => format!(
+                "write {} {} {}",
+                self.operand(value),
+                self.operand(cell),
+                self.operand(index)
+            ),
+            Statement::Draw { command, args } => {
+                format!("draw {} {}", command, self.operands(args, 6))
+            }
+            Statement::Print { value } => format!("print {}", self.operand(value)),
+            Statement::DrawFlush { display } => format!("drawflush {}", self.operand(display)),
+            Statement::PrintFlush { message } => format!("printflush {}", self.operand(message)),
+            Statement::GetLink { result, index } => format!(
+                "getlink {} {}",
+                self.name(&result.name),
+                self.operand(index)
+            ),
+            Statement::Control {
+                command,
+                block,
+                args,
+            } => format!(
+                "control {} {} {}",
+                command,
+                self.operand(block),
+                self.operands(args, 4)
+            ),
+            Statement::Radar {
+                filters: [f1, f2, f3],
+                sort,
+                from,
+                order,
+                result,
+            } => format!(
+                "radar {f1} {f2} {f3} {sort} {} {} {}",
+                self.operand(from),
+                self.operand(order),
+                self.name(&result.name)
+            ),
+            Statement::Sensor {
+                result,
+                target,
+                attrib,
+            } => format!(
+                "sensor {} {} {}",
+                self.name(&result.name),
+                self.operand(target),
+                self.operand(attrib)
+            ),
+            Statement::Lookup {
+                result,
+                kind,
+                index,
+            } => format!(
+                "lookup {kind} {} {}",
+                self.name(&result.name),
+                self.operand(index)
+            ),
+            Statement::PackColor { result, r, g, b, a } => format!(
+                "packcolor {} {} {} {} {}",
+                self.name(&result.name),
+                self.operand(r),
+                self.operand(g),
+                self.operand(b),
+                self.operand(a)
+            ),
+            Statement::Wait { time } => format!("wait {}", self.operand(time)),
+            Statement::Stop => "stop".to_owned(),
+            Statement::End => "end".to_owned(),
+            Statement::UnitBind { unit_type } => format!("ubind {}", self.operand(unit_type)),
+            Statement::UnitControl { command, args } => {
+                format!("ucontrol {} {}", command, self.operands(args, 5))
+            }
+            // uradar没有来源，对应的位置固定为0
+            Statement::UnitRadar {
+                filters: [f1, f2, f3],
+                sort,
+                order,
+                result,
+            } => format!(
+                "uradar {f1} {f2} {f3} {sort} 0 {} {}",
+                self.operand(order),
+                self.name(&result.name)
+            ),
+            Statement::UnitLocate {
+                kind,
+                group,
+                enemy,
+                ore,
+                out_x,
+                out_y,
+                found,
+                building,
+            } => format!(
+                "ulocate {kind} {group} {} {} {} {} {} {}",
+                self.operand(enemy),
+                self.operand(ore),
+                self.name(&out_x.name),
+                self.name(&out_y.name),
+                self.name(&found.name),
+                self.name(&building.name)
+            ),
+        }
+    }
+
+    /// 出口按照不依赖布局的方式输出，修改了`@counter`的出口没有额外的语句
+    fn exit(&mut self, exit: &Exit) -> Vec<String> {
+        match exit {
+            Exit::Goto(target) => vec![format!("jump {target} always")],
+            Exit::Branch {
+                left,
+                cond,
+                right,
+                then,
+                r#else,
+            } => vec![
+                format!(
+                    "jump {then} {} {} {}",
+                    condition_name(cond),
+                    self.operand(left),
+                    self.operand(right)
+                ),
+                format!("jump {} always", r#else),
+            ],
+            Exit::Call {
+                addr, entry, ret, ..
+            } => vec![
+                format!("op add {} @counter 1", self.name(&addr.name)),
+                format!("jump {entry} always"),
+                format!("jump {ret} always"),
+            ],
+            Exit::Indirect(..) => vec![],
+            Exit::End => vec!["end".to_owned()],
+            Exit::Stop => vec!["stop".to_owned()],
+        }
+    }
+
+    /// 子命令的参数，不足的部分补零
+    fn operands(&mut self, args: &[Variable], len: usize) -> String {
+        let mut operands: Vec<_> = args.iter().map(|arg| self.operand(arg)).collect();
+        operands.resize(len.max(args.len()), "0".to_owned());
+        operands.join(" ")
+    }
+
+    /// 作为左值时只能输出名字
+    fn name(&mut self, name: &VariableName) -> String {
+        match name {
+            VariableName::Named(name) => name.clone(),
+            VariableName::Index(index) => {
+                let next = self.indexs.len();
+                let index = *self.indexs.entry(*index).or_insert(next);
+                format!("__t{index}")
+            }
+            VariableName::None => "null".to_owned(),
+        }
+    }
+
+    /// 作为右值时，已知的常量直接输出字面量
+    fn operand(&mut self, var: &Variable) -> String {
+        match &var.value {
+            VariableValue::Number(number) => self::number(*number),
+            VariableValue::Color(rgba) => self::color(*rgba),
+            VariableValue::String(string) => self::string(string),
+            _ => self.name(&var.name),
+        }
+    }
+}
+
+/// 按照游戏能够解析的格式输出数字
+///
+/// * 整数不带小数点
+/// * 过大或过小的数使用科学计数法
+/// * 游戏中不是有限的数会被当作0，比如过大的字面量
+pub fn number(number: f64) -> String {
+    // -0 会被输出为 "-0"
+    if number == 0.0 || !number.is_finite() {
+        return "0".to_owned();
+    }
+    let abs = number.abs();
+    if !(1e-5..1e15).contains(&abs) {
+        format!("{number:e}")
+    } else {
+        format!("{number}")
+    }
+}
+
+/// 颜色常量输出为`%rrggbbaa`
+pub fn color(rgba: u32) -> String {
+    format!("%{rgba:08x}")
+}
+
+/// mlog的字符串中不能转义引号，换行以`\n`的形式书写
+pub fn string(string: &str) -> String {
+    let escaped = string.replace('\n', "\\n").replace('"', "'");
+    format!("\"{escaped}\"")
+}
+
+fn op_name(symbol: Symbol) -> &'static str {
+    match symbol {
+        Symbol::Add => "add",
+        Symbol::Sub => "sub",
+        Symbol::Mul => "mul",
+        Symbol::Div => "div",
+        Symbol::IDiv => "idiv",
+        Symbol::Rem => "mod",
+        Symbol::Pow => "pow",
+        Symbol::Eq => "equal",
+        Symbol::Neq => "notEqual",
+        // `&&`和`||`会短路，不会生成op
+        Symbol::Lr => "lessThan",
+        Symbol::Gr => "greaterThan",
+        Symbol::Seq => "strictEqual",
+        Symbol::LrE => "lessThanEq",
+        Symbol::GrE => "greaterThanEq",
+        Symbol::Shl => "shl",
+        Symbol::Shr => "shr",
+        Symbol::Band => "and",
+        Symbol::Bor => "or",
+        Symbol::Xor => "xor",
+        Symbol::Flip => "not",
+        // `!x` 生成为 `op Not r x 0`，也就是 `x == 0`
+        Symbol::Not => "equal",
+        _ => unreachable!("{symbol} 不是运算符"),
+    }
+}
+
+fn condition_name(cond: &JumpCondition) -> &'static str {
+    match cond {
+        JumpCondition::Eq => "equal",
+        JumpCondition::Neq => "notEqual",
+        JumpCondition::Lr => "lessThan",
+        JumpCondition::LrE => "lessThanEq",
+        JumpCondition::Gr => "greaterThan",
+        JumpCondition::GrE => "greaterThanEq",
+        JumpCondition::Seq => "strictEqual",
+        JumpCondition::Always => "always",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::mlog;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(number(1.0), "1");
+        assert_eq!(number(-0.0), "0");
+        assert_eq!(number(0.25), "0.25");
+        assert_eq!(number(1e20), "1e20");
+        assert_eq!(number(f64::NAN), "0");
+        assert_eq!(number(f64::INFINITY), "0");
+        // 除以0不折叠，由游戏计算
+        assert_eq!(crate::abi::fold(Symbol::Div, 1.0, 0.0), None);
+        // 只有标记为颜色的常量才输出为`%rrggbbaa`
+        assert_eq!(number(f64::from_bits(1)), "5e-324");
+        assert_eq!(color(0xff8800ff), "%ff8800ff");
+        assert_eq!(string("a\"b\nc"), "\"a'b\\nc\"");
+    }
+
+    #[test]
+    fn ir() {
+        let src = "let x = 0\nwhile x < 10 {\n    x = x + 1\n}\n";
+        let cfg = crate::testing::generate(src, crate::opt::Level::O0).unwrap();
+        let ir = emit_ir(&cfg);
+        // 标签来自全局计数器，只检查语句
+        let stmts: Vec<_> = ir
+            .lines()
+            .filter_map(|line| line.strip_prefix("    "))
+            .collect();
+        assert!(stmts.contains(&"set x 0"), "{ir}");
+        assert!(stmts.contains(&"op add x x 1"), "{ir}");
+        assert!(
+            stmts
+                .iter()
+                .any(|stmt| stmt.ends_with(" greaterThanEq x 10")),
+            "{ir}"
+        );
+        assert_eq!(
+            ir.lines().filter(|line| line.ends_with(':')).count(),
+            cfg.blocks().len()
+        );
+    }
+
+    #[test]
+    fn while_loop() {
+        let src = "let x, y\nwhile x < 10 {\n    y = x + 1\n    x = y * 2\n}\n";
+        assert_eq!(
+            mlog(src),
+            "jump 4 greaterThanEq x 10\n\
+             op add y x 1\n\
+             op mul x y 2\n\
+             jump 0 always 0 0\n"
+        );
+    }
+
+    #[test]
+    fn function() {
+        let src = "fn add(a, b) {\n    let c = a + b\n}\nlet x\nadd(x, 1)\n";
+        assert_eq!(
+            mlog(src),
+            "set add.a x\n\
+             set add.b 1\n\
+             op add add.addr @counter 1\n\
+             jump 6 always 0 0\n\
+             set __t0 add.ret\n\
+             jump 0 always 0 0\n\
+             op add add.c add.a add.b\n\
+             set add.ret null\n\
+             set @counter add.addr\n"
+        );
+    }
+
+    #[test]
+    fn instructions() {
+        use crate::abi::*;
+        let named =
+            |name: &str| Variable::new(VariableName::named(name), VariableValue::UnknowType);
+        let number = |n: f64| Variable::new(VariableName::None, VariableValue::Number(n));
+
+        let mut stmts = crate::cfg::Program::default();
+        stmts.push_stmt(Statement::Draw {
+            command: DrawCommand::Rect,
+            args: vec![number(1.0), number(2.0), named("w"), named("h")],
+        });
+        stmts.push_stmt(Statement::Radar {
+            filters: [Radar::Enemy, Radar::Flying, Radar::Any],
+            sort: RadarSort::Distance,
+            from: named("turret1"),
+            order: number(1.0),
+            result: named("target"),
+        });
+        stmts.push_stmt(Statement::UnitControl {
+            command: UnitControlCommand::Move,
+            args: vec![named("x"), named("y")],
+        });
+        stmts.push_stmt(Statement::UnitLocate {
+            kind: UnitLocateKind::Building,
+            group: BuildingGroup::Core,
+            enemy: number(0.0),
+            ore: named("@copper"),
+            out_x: named("cx"),
+            out_y: named("cy"),
+            found: named("found"),
+            building: named("core"),
+        });
+        stmts.push_stmt(Statement::End);
+        assert_eq!(
+            emit(&stmts),
+            "draw rect 1 2 w h 0 0\n\
+             radar enemy flying any distance turret1 1 target\n\
+             ucontrol move x y 0 0 0\n\
+             ulocate building core 0 @copper cx cy found core\n\
+             end\n"
+        );
+        assert_eq!(LookUp::Item.counter(), "@itemCount");
+        assert_eq!(
+            DrawCommand::from_name("lineRect"),
+            Some(DrawCommand::LineRect)
+        );
+    }
+}
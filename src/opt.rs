@@ -0,0 +1,557 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    abi::{Statement, Variable, VariableName, VariableValue},
+    cfg::{Cfg, Exit},
+};
+
+/// 控制流图上的优化，返回是否修改了控制流图
+pub struct Pass {
+    /// 在命令行中使用的名字
+    pub name: &'static str,
+    pub run: fn(&mut Cfg) -> bool,
+}
+
+pub const PASSES: &[Pass] = &[
+    Pass {
+        name: "const-prop",
+        run: const_prop,
+    },
+    Pass {
+        name: "copy-prop",
+        run: copy_prop,
+    },
+    Pass {
+        name: "dse",
+        run: dead_store,
+    },
+    Pass {
+        name: "thread",
+        run: thread_jumps,
+    },
+    Pass {
+        name: "unreachable",
+        run: unreachable,
+    },
+];
+
+pub fn lookup(name: &str) -> Option<&'static Pass> {
+    PASSES.iter().find(|pass| pass.name == name)
+}
+
+pub fn names() -> Vec<&'static str> {
+    PASSES.iter().map(|pass| pass.name).collect()
+}
+
+/// 优化等级，决定启用的pass，以及代码生成时在大小和速度之间的取舍
+///
+/// 处理器最多只能容纳1000条指令，每tick能执行的指令也有限
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Level {
+    /// 不做任何优化，生成的代码和源码一一对应
+    O0,
+    /// 尽量减小代码的大小
+    #[default]
+    Os,
+    /// 尽量减少执行的指令，代码可能更大
+    O2,
+}
+
+/// 调用函数时保存返回地址和跳转需要的语句数，参数和返回值的赋值不论是否展开都需要
+pub const CALL_LEN: usize = 2;
+
+impl Level {
+    /// 函数体在调用处展开后有`len`条语句时，是否展开
+    ///
+    /// `-Os`只展开不比调用更长的函数，每一处调用都不会变大，
+    /// 全部调用都被展开后，函数体也会因为不可达而被移除
+    pub fn inline(self, len: usize) -> bool {
+        match self {
+            Self::O0 => false,
+            Self::Os => len <= CALL_LEN,
+            Self::O2 => len <= 32,
+        }
+    }
+
+    /// 次数已知的for循环展开之后不超过这个长度时，直接展开
+    pub fn unroll_limit(self) -> usize {
+        match self {
+            Self::O0 | Self::Os => 0,
+            Self::O2 => 64,
+        }
+    }
+}
+
+impl std::str::FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let level = match s {
+            "0" => Self::O0,
+            "s" => Self::Os,
+            "2" => Self::O2,
+            _ => return Err(format!("未知的优化等级 `-O{s}`，应为 -O0 | -Os | -O2 之一")),
+        };
+        Ok(level)
+    }
+}
+
+/// 一个pass的结果可能让其他pass有新的机会，所以反复运行，直到没有变化
+const MAX_ROUNDS: usize = 16;
+
+/// 按照`PASSES`中的顺序运行启用的pass
+#[derive(Debug, Clone)]
+pub struct PassManager {
+    enabled: Vec<&'static str>,
+}
+
+impl PassManager {
+    /// `-O0`不启用任何pass，其他等级启用全部的pass
+    pub fn new(level: Level) -> Self {
+        let enabled = match level {
+            Level::O0 => vec![],
+            Level::Os | Level::O2 => names(),
+        };
+        Self { enabled }
+    }
+
+    pub fn set(&mut self, name: &'static str, enable: bool) {
+        self.enabled.retain(|enabled| *enabled != name);
+        if enable {
+            self.enabled.push(name);
+        }
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.enabled.contains(&name)
+    }
+
+    pub fn run(&self, cfg: &mut Cfg) {
+        for _ in 0..MAX_ROUNDS {
+            let mut changed = false;
+            for pass in PASSES.iter().filter(|pass| self.is_enabled(pass.name)) {
+                changed |= (pass.run)(cfg);
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+}
+
+/// 执行到某条语句之前，值一定是某个常量的变量
+type Consts = HashMap<VariableName, f64>;
+
+/// 常量传播和折叠
+///
+/// mlog的变量在回到第一条语句之后仍然保留，所以开头时所有变量的值都是未知的。
+/// 沿着控制流迭代到不动点，只有在所有能到达的路径上都相同的常量才会被替换，
+/// 循环中被重新赋值的变量因此不会被折叠。条件已知的分支会变为无条件跳转
+pub fn const_prop(cfg: &mut Cfg) -> bool {
+    if cfg.blocks().is_empty() {
+        return false;
+    }
+    let successors = cfg.successors();
+    let positions = cfg.positions();
+
+    // 每个块开头的常量，None表示还没有找到能到达这个块的路径
+    let mut states: Vec<Option<Consts>> = vec![None; successors.len()];
+    states[0] = Some(Consts::new());
+    let mut work = vec![0];
+    while let Some(index) = work.pop() {
+        let mut state = states[index].clone().unwrap();
+        let block = &cfg.blocks()[index];
+        for stmt in &block.stmts {
+            transfer(stmt, &mut state);
+        }
+        // 条件恒定的分支只走一边
+        let nexts = match (&block.exit, branch_taken(&block.exit, &state)) {
+            (Exit::Branch { then, .. }, Some(true)) => vec![positions[then]],
+            (Exit::Branch { r#else, .. }, Some(false)) => vec![positions[r#else]],
+            _ => successors[index].clone(),
+        };
+        for result in block.exit.results() {
+            state.remove(&result.name);
+        }
+        for next in nexts {
+            if merge(&mut states[next], &state) {
+                work.push(next);
+            }
+        }
+    }
+
+    let mut changed = false;
+    for (block, state) in cfg.blocks_mut().iter_mut().zip(states) {
+        let Some(mut state) = state else {
+            continue;
+        };
+        for stmt in &mut block.stmts {
+            changed |= substitute(stmt.operands_mut(), &state);
+            if let Statement::Operation { result, op, v1, v2 } = stmt {
+                let folded = match (v1.value.as_number(), v2.value.as_number()) {
+                    (Some(&l), Some(&r)) => crate::abi::fold(*op, l, r),
+                    _ => None,
+                };
+                if let Some(number) = folded {
+                    let value = Variable::new(VariableName::None, VariableValue::Number(number));
+                    *stmt = Statement::set(result.clone(), value);
+                    changed = true;
+                }
+            }
+            transfer(stmt, &mut state);
+        }
+
+        changed |= substitute(block.exit.operands_mut(), &state);
+        match (&block.exit, branch_taken(&block.exit, &state)) {
+            (Exit::Branch { then, .. }, Some(true)) => block.exit = Exit::Goto(*then),
+            (Exit::Branch { r#else, .. }, Some(false)) => block.exit = Exit::Goto(*r#else),
+            _ => continue,
+        }
+        changed = true;
+    }
+    changed
+}
+
+/// 把已知是常量的操作数替换为字面量，返回是否有替换
+fn substitute(operands: Vec<&mut Variable>, state: &Consts) -> bool {
+    let mut changed = false;
+    for operand in operands {
+        if operand.value.is_number() {
+            continue;
+        }
+        if let Some(number) = value_of(operand, state) {
+            operand.value = VariableValue::Number(number);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// 复制传播：只被赋值一次的临时变量，在同一个基本块中直接使用它的值
+///
+/// 值是`@`开头的元变量时不会传播，因为它可能被游戏修改
+pub fn copy_prop(cfg: &mut Cfg) -> bool {
+    let mut defs: HashMap<VariableName, usize> = HashMap::new();
+    for block in cfg.blocks() {
+        let stmt_results = block.stmts.iter().flat_map(Statement::results);
+        for result in stmt_results.chain(block.exit.results()) {
+            *defs.entry(result.name.clone()).or_default() += 1;
+        }
+    }
+
+    let mut changed = false;
+    for block in cfg.blocks_mut() {
+        for index in 0..block.stmts.len() {
+            let Statement::Set { result, value } = &block.stmts[index] else {
+                continue;
+            };
+            if !result.name.is_index() || defs[&result.name] != 1 {
+                continue;
+            }
+            let stable = value.value.is_number()
+                || value.value.is_string()
+                || (tracked(&value.name) && !value.value.is_meta_attrib());
+            if !stable || value.name == result.name {
+                continue;
+            }
+            let (temp, value) = (result.name.clone(), value.clone());
+
+            let mut replace = |operands: Vec<&mut Variable>| {
+                for operand in operands {
+                    if operand.name == temp {
+                        *operand = value.clone();
+                        changed = true;
+                    }
+                }
+            };
+            let mut redefined = false;
+            for stmt in &mut block.stmts[index + 1..] {
+                replace(stmt.operands_mut());
+                // 值被重新赋值之后，就不能再使用它了
+                if stmt
+                    .results()
+                    .iter()
+                    .any(|result| result.name == value.name)
+                {
+                    redefined = true;
+                    break;
+                }
+            }
+            if !redefined {
+                replace(block.exit.operands_mut());
+            }
+        }
+    }
+    changed
+}
+
+/// 死存储消除：移除结果从未被读取的`set`和`op`
+///
+/// `@`开头的元变量，比如`@counter`，不会被移除
+pub fn dead_store(cfg: &mut Cfg) -> bool {
+    let mut reads = HashSet::new();
+    for block in cfg.blocks() {
+        let mut block = block.clone();
+        let stmt_operands = block.stmts.iter_mut().flat_map(Statement::operands_mut);
+        for operand in stmt_operands.chain(block.exit.operands_mut()) {
+            // 已知的常量会直接输出字面量，不会读取变量
+            if !operand.value.is_number() && !operand.value.is_string() {
+                reads.insert(operand.name.clone());
+            }
+        }
+    }
+
+    let mut changed = false;
+    for block in cfg.blocks_mut() {
+        changed |= block.retain(|stmt| match stmt {
+            Statement::Set { result, value } if result.name == value.name => false,
+            Statement::Set { result, .. } | Statement::Operation { result, .. } => {
+                !tracked(&result.name) || reads.contains(&result.name)
+            }
+            _ => true,
+        });
+    }
+    changed
+}
+
+/// 跳转穿透：跳转到只有无条件跳转的块时，直接跳转到它的目标
+///
+/// 两边相同的分支会变为无条件跳转，跳转到下一个块的跳转在排布时省略
+pub fn thread_jumps(cfg: &mut Cfg) -> bool {
+    let positions = cfg.positions();
+    // 从最后一个块回到入口不需要跳转，所以不穿过入口
+    let forward: HashMap<_, _> = cfg
+        .blocks()
+        .iter()
+        .skip(1)
+        .filter_map(|block| match block.exit {
+            Exit::Goto(target) if block.stmts.is_empty() => Some((block.label, target)),
+            _ => None,
+        })
+        .collect();
+
+    let mut changed = false;
+    for block in cfg.blocks_mut() {
+        for target in block.exit.targets_mut() {
+            let mut new_target = *target;
+            // 最多穿过块的数量次，避免死循环
+            for _ in 0..positions.len() {
+                match forward.get(&new_target) {
+                    Some(&next) if next != new_target => new_target = next,
+                    _ => break,
+                }
+            }
+            if new_target != *target {
+                *target = new_target;
+                changed = true;
+            }
+        }
+        if let Exit::Branch { then, r#else, .. } = block.exit {
+            if then == r#else {
+                block.exit = Exit::Goto(then);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// 移除从入口出发无法到达的块
+pub fn unreachable(cfg: &mut Cfg) -> bool {
+    if cfg.blocks().is_empty() {
+        return false;
+    }
+    let successors = cfg.successors();
+    let mut reached = vec![false; successors.len()];
+    reached[0] = true;
+    let mut work = vec![0];
+    while let Some(index) = work.pop() {
+        for &next in &successors[index] {
+            if !reached[next] {
+                reached[next] = true;
+                work.push(next);
+            }
+        }
+    }
+    if reached.iter().all(|&reached| reached) {
+        return false;
+    }
+    cfg.retain(&reached);
+    true
+}
+
+/// 可以作为常量传播的变量，`@`开头的元变量可能被游戏修改
+fn tracked(name: &VariableName) -> bool {
+    match name {
+        VariableName::Named(name) => !name.starts_with('@'),
+        VariableName::Index(..) => true,
+        VariableName::None => false,
+    }
+}
+
+fn value_of(var: &Variable, state: &Consts) -> Option<f64> {
+    match &var.value {
+        VariableValue::Number(number) => Some(*number),
+        _ if tracked(&var.name) => state.get(&var.name).copied(),
+        _ => None,
+    }
+}
+
+/// 两边都是常量的分支是否跳转到then
+fn branch_taken(exit: &Exit, state: &Consts) -> Option<bool> {
+    match exit {
+        Exit::Branch {
+            left, cond, right, ..
+        } => Some(cond.eval(value_of(left, state)?, value_of(right, state)?)),
+        _ => None,
+    }
+}
+
+fn transfer(stmt: &Statement, state: &mut Consts) {
+    let value = match stmt {
+        Statement::Set { value, .. } => value_of(value, state),
+        Statement::Operation { op, v1, v2, .. } => {
+            match (value_of(v1, state), value_of(v2, state)) {
+                (Some(l), Some(r)) => crate::abi::fold(*op, l, r),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    for result in stmt.results() {
+        match value {
+            Some(number) if tracked(&result.name) => {
+                state.insert(result.name.clone(), number);
+            }
+            _ => {
+                state.remove(&result.name);
+            }
+        }
+    }
+}
+
+/// 合并另一条路径上的常量，返回是否有变化
+fn merge(into: &mut Option<Consts>, from: &Consts) -> bool {
+    match into {
+        None => {
+            *into = Some(from.clone());
+            true
+        }
+        Some(into) => {
+            let len = into.len();
+            into.retain(|name, number| from.get(name) == Some(number));
+            into.len() != len
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(src: &str) -> String {
+        let mut passes = PassManager::new(Level::O0);
+        passes.set("const-prop", true);
+        compile_with(src, Level::O0, &passes)
+    }
+
+    fn compile_with(src: &str, level: Level, passes: &PassManager) -> String {
+        crate::mlog::emit(&crate::testing::compile(src, level, passes))
+    }
+
+    /// 以`prefix`开头的指令，用来检查结构而不依赖具体的排布
+    fn find<'a>(mlog: &'a str, prefix: &str) -> Vec<&'a str> {
+        mlog.lines()
+            .filter(|line| line.starts_with(prefix))
+            .collect()
+    }
+
+    #[test]
+    fn loops() {
+        // 循环中被重新赋值的变量不能使用循环之前的值
+        let src = "let y = 0\nlet n = 10\nwhile y < n {\n    y += 1\n}\nprint(y, n)\n";
+        let mlog = compile(src);
+        assert_eq!(find(&mlog, "print"), ["print y", "print 10"], "{mlog}");
+        assert_eq!(find(&mlog, "op add"), ["op add y y 1"], "{mlog}");
+        assert!(mlog.contains("greaterThanEq y 10"), "{mlog}");
+    }
+
+    #[test]
+    fn branches() {
+        // 只有两条路径上相同的常量才能折叠
+        let src = "let x\nlet a, b = 2, 2\nif x {\n    a = 3\n    b = 2\n}\nlet c = a * b\nif 1 {\n    c = 0\n}\n";
+        let mlog = compile(src);
+        assert_eq!(find(&mlog, "op mul"), ["op mul c a 2"], "{mlog}");
+        // 条件恒为真的分支总会执行
+        assert!(mlog.ends_with("set c 0\n"), "{mlog}");
+    }
+
+    #[test]
+    fn functions() {
+        // 函数会修改全局变量，返回之后不能使用调用之前的值
+        let src = "let g = 1\nfn f(x) {\n    g = x\n}\nf(2)\nprint(g)\nf(3)\nprint(g)\n";
+        let mlog = compile(src);
+        assert_eq!(find(&mlog, "print"), ["print g", "print g"], "{mlog}");
+        assert_eq!(find(&mlog, "op add f.addr").len(), 2, "{mlog}");
+    }
+
+    #[test]
+    fn pipeline() {
+        // 常量传播之后，对`g`和`f.x`的赋值以及没有使用的返回值都被移除
+        let src = "let g = 1\nfn f(x) {\n    g = x\n}\nf(2)\nprint(g)\n";
+        let mlog = compile_with(src, Level::O0, &PassManager::new(Level::Os));
+        assert_eq!(find(&mlog, "print"), ["print 2"], "{mlog}");
+        assert!(find(&mlog, "set")
+            .iter()
+            .all(|set| set.starts_with("set @counter")));
+
+        // 不会执行的分支被移除
+        let src = "let x\nlet a = 2\nif x {\n    a = 3\n} else if 0 {\n    a = 4\n}\nprint(a)\n";
+        let mlog = compile_with(src, Level::O0, &PassManager::new(Level::Os));
+        assert!(!mlog.contains("set a 4"), "{mlog}");
+        assert_eq!(find(&mlog, "jump").len(), 1, "{mlog}");
+        assert_eq!(find(&mlog, "print"), ["print a"], "{mlog}");
+    }
+
+    #[test]
+    fn levels() {
+        let src = "let n\nfn add(a) {\n    n += a\n}\nfor i in 0..3 {\n    add(i)\n}\n";
+        let compile = |level| compile_with(src, level, &PassManager::new(level));
+
+        let o0 = compile(Level::O0);
+        assert_eq!(find(&o0, "op add add.addr").len(), 1, "{o0}");
+        assert_eq!(find(&o0, "set @counter add.addr").len(), 1, "{o0}");
+        // 一条语句的函数被展开
+        let os = compile(Level::Os);
+        assert!(!os.contains("add.addr"), "{os}");
+        assert_eq!(find(&os, "op add n n").len(), 1, "{os}");
+        assert!(os.lines().count() < o0.lines().count());
+        // 循环也被展开
+        let o2 = compile(Level::O2);
+        assert!(find(&o2, "jump").is_empty(), "{o2}");
+        assert_eq!(find(&o2, "op add n n").len(), 3, "{o2}");
+    }
+
+    #[test]
+    fn inline_size() {
+        let calls = "let x\nf(x)\nf(x)\nf(x)\nf(x)\nf(x)\n";
+        let compile = |body: &str, level| {
+            let src = format!("let n\nfn f(a) {{\n{body}}}\n{calls}");
+            compile_with(&src, level, &PassManager::new(level))
+        };
+        let lines = |mlog: String| mlog.lines().count();
+
+        // 展开后比调用更长的函数在`-Os`时不展开，`-O2`时展开
+        let body = "    n += a\n    n *= a\n    n -= 1\n";
+        let os = compile(body, Level::Os);
+        assert_eq!(find(&os, "op add f.addr @counter 1").len(), 5, "{os}");
+        assert!(lines(os) <= lines(compile(body, Level::O0)));
+        assert!(!compile(body, Level::O2).contains("f.addr"));
+
+        // 加上隐式返回的null和调用一样长的函数被展开，函数体被移除
+        let body = "    n += a\n";
+        let os = compile(body, Level::Os);
+        assert!(!os.contains("f.addr"), "{os}");
+        assert!(lines(os) <= lines(compile(body, Level::O0)));
+    }
+}
@@ -2,7 +2,7 @@ use std::fmt::Debug;
 
 use crate::{
     error::{Error, ErrorKind},
-    lexer::{Symbol, Token},
+    lexer::{Symbol, Token, TokenVul},
     syn,
 };
 
@@ -10,11 +10,51 @@ use crate::{
 pub struct Parser<'a> {
     index: usize,
     tokens: &'a [Token],
+    /// 已经恢复了的错误
+    errors: Vec<Error>,
 }
 
 impl Parser<'_> {
-    pub fn new(tokens: &[Token]) -> Parser {
-        Parser { tokens, index: 0 }
+    pub fn new(tokens: &[Token]) -> Parser<'_> {
+        Parser {
+            tokens,
+            index: 0,
+            errors: vec![],
+        }
+    }
+
+    pub fn peek_token(&self) -> Option<&Token> {
+        self.tokens.get(self.index)
+    }
+
+    /// 记录一个错误，之后由调用者负责跳过出错的部分
+    pub fn report(&mut self, error: Error) {
+        self.errors.push(error);
+    }
+
+    /// 出错后重新同步：跳过当前语句
+    ///
+    /// 一直跳到换行(会被消耗)，或者是没有配对的`}`(不会被消耗，交给语句块处理)，
+    /// 中间成对出现的`{}`会被整个跳过
+    pub fn synchronize(&mut self) {
+        let mut depth = 0usize;
+        while let Some(token) = self.tokens.get(self.index) {
+            match &token.vul {
+                TokenVul::EndLine if depth == 0 => {
+                    self.index += 1;
+                    return;
+                }
+                TokenVul::Symbol(Symbol::SpaceL) => depth += 1,
+                TokenVul::Symbol(Symbol::SpaceR) => {
+                    if depth == 0 {
+                        return;
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+            self.index += 1;
+        }
     }
 
     pub fn get_next_token(&mut self) -> Option<&'static Token> {
@@ -34,13 +74,11 @@ impl Parser<'_> {
         if e.is_none() {
             return Error::empty();
         }
-        let token = if self.index >= self.tokens.len() {
-            self.tokens.last().unwrap()
-        } else {
-            &self.tokens[self.index]
-        };
-
-        e.generate_error(token)
+        // 到达结尾时指向最后一个token，空文件指向开头
+        match self.tokens.get(self.index).or(self.tokens.last()) {
+            Some(token) => e.generate_error(token),
+            None => e.make_error(crate::lexer::Location::new(0, 0, 0)),
+        }
     }
 
     pub fn l_err<E>(&mut self, e: E, token: &Token) -> Error
@@ -54,18 +92,26 @@ impl Parser<'_> {
         e.generate_error(token)
     }
 
-    pub fn get_compile_units(&mut self) -> Result<Vec<Box<dyn syn::CompileUnit>>, Error> {
-        let compile_units = crate::ast::parse_compile_units(self)?;
-        if self.index != self.tokens.len() {
-            // 报错
-            crate::ast::parse_compile_unit(self)?;
+    /// 解析整个文件，返回能够解析的部分，以及所有的错误
+    pub fn get_compile_units(&mut self) -> (Vec<Box<dyn syn::CompileUnit>>, Vec<Error>) {
+        let mut compile_units = vec![];
+        loop {
+            // 错误都被记录在self.errors中
+            if let Ok(units) = crate::ast::parse_compile_units(self) {
+                compile_units.extend(units);
+            }
+            // 只有多余的"}"会使得parse_compile_units提前结束
+            match self.get_next_token() {
+                Some(token) => self.report(ErrorKind::UnExpect.generate_error(token)),
+                None => break,
+            }
         }
-        Ok(compile_units)
+        (compile_units, std::mem::take(&mut self.errors))
     }
 }
 
 macro_rules! generate_getter {
-    ($getter : ident,$matcher : ident,$v : ident,$ret : ty) => {
+    ($getter : ident,$v : ident,$ret : ty) => {
         pub fn $getter<E>(&mut self, or: E) -> Result<(&'static Token, &'static $ret), Error>
         where
             E: FnOnce() -> ErrorKind,
@@ -78,6 +124,9 @@ macro_rules! generate_getter {
                 None => Err(self.ul_err(or)),
             }
         }
+    };
+    ($getter : ident,$matcher : ident,$v : ident,$ret : ty) => {
+        generate_getter! {$getter, $v, $ret}
 
         pub fn $matcher<E>(&mut self, matcher: &$ret, or: E) -> Result<&'static Token, Error>
         where
@@ -95,8 +144,9 @@ macro_rules! generate_getter {
 
 impl Parser<'_> {
     generate_getter! {get_ident,match_ident, Ident, String}
-    generate_getter! {get_string, match_string,String, String}
-    generate_getter! {get_number, match_number,Number, f64}
+    generate_getter! {get_string, String, String}
+    generate_getter! {get_number, Number, f64}
+    generate_getter! {get_color, Color, u32}
     generate_getter! {get_symbol, match_symbol,Symbol, Symbol}
     pub fn match_endlines(&mut self) -> Result<(), Error> {
         fn e() -> ErrorKind {
@@ -144,6 +194,7 @@ impl<T> Try<'_, '_, T> {
                 self.state = Some(Ok(t));
 
                 self.parser.index = temp.index;
+                self.parser.errors.extend(temp.errors);
             }
 
             Err(e) => {
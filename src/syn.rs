@@ -1,10 +1,15 @@
-use std::fmt::{Debug, Display};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+};
 
 use crate::{
-    abi::{Statement, Variable, VariableName, VariableValue},
+    abi::{JumpCondition, LookUp, Statement, Variable, VariableName, VariableValue},
     ast,
-    error::Error,
-    meta::GlobalSpace,
+    cfg::{Block, Exit, Label},
+    error::{Error, ErrorKind, WarnKind},
+    lexer::Location,
+    meta::{FnDefine, GlobalSpace},
 };
 
 #[derive(Debug)]
@@ -21,48 +26,244 @@ impl CompileUnit for EmptyStmt {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Statements {
-    stmts: Vec<Statement>,
+/// 语句的标记，排布为mlog之后用来找到函数调用、返回和draw指令
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mark {
+    /// 调用函数 函数的编号
+    Call(usize),
+    /// draw指令在源文件中的位置
+    Draw { location: Location, source: usize },
 }
 
-impl std::ops::Index<usize> for Statements {
-    type Output = Statement;
+/// 带有标记的语句，标记中的序号是语句的位置
+#[derive(Debug, Clone, Default)]
+pub struct Marked {
+    pub stmts: Vec<Statement>,
+    pub marks: Vec<(usize, Mark)>,
+}
 
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.stmts[index]
+impl Marked {
+    pub fn push_stmt(&mut self, stmt: Statement) {
+        self.stmts.push(stmt);
     }
-}
 
-impl std::ops::IndexMut<usize> for Statements {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.stmts[index]
+    /// 标记上一条语句
+    pub fn mark_last(&mut self, mark: Mark) {
+        self.marks.push((self.stmts.len() - 1, mark));
     }
 }
 
+/// 处理器的绘图缓冲区能容纳的指令数
+pub const DRAW_BUFFER: usize = 256;
+
+/// 代码生成的结果，由以标签跳转的基本块组成
+///
+/// 最后一个块还在生成中，出口要等到之后的跳转或者标签才能确定
+#[derive(Debug, Clone)]
+pub struct Statements {
+    /// 已经确定了出口的块
+    blocks: Vec<Block>,
+    /// 正在生成的块的标签
+    label: Label,
+    /// 正在生成的块中的语句和标记
+    block: Marked,
+    /// 正在生成的块是否不可达，这时它的标签没有被任何跳转引用
+    dead: bool,
+    /// 还没有放置的函数体
+    fns: Vec<Statements>,
+}
+
 impl Statements {
     pub fn new() -> Self {
-        Self { stmts: vec![] }
+        Self::with_entry(Label::alloc())
+    }
+
+    /// 第一个块的标签为`entry`，用于函数体
+    pub fn with_entry(entry: Label) -> Self {
+        Self {
+            blocks: vec![],
+            label: entry,
+            block: Marked::default(),
+            dead: false,
+            fns: vec![],
+        }
+    }
+
+    /// 第一个块的标签
+    pub fn entry(&self) -> Label {
+        self.blocks.first().map_or(self.label, |block| block.label)
+    }
+
+    /// 按照生成的顺序排布时的语句数，包括出口生成的跳转
+    pub fn len(&self) -> usize {
+        let labels = self.blocks.iter().map(|block| block.label).skip(1);
+        let nexts = labels.chain(std::iter::once(self.label));
+        let exits: usize = self
+            .blocks
+            .iter()
+            .zip(nexts)
+            .map(|(block, next)| block.stmts.len() + block.exit.len(next))
+            .sum();
+        exits + self.block.stmts.len()
     }
 
     pub fn push_stmt(&mut self, stmt: Statement) {
-        self.stmts.push(stmt);
+        self.block.push_stmt(stmt);
+    }
+
+    /// 以`exit`结束正在生成的块，之后的语句放到标签为`next`的块中
+    fn close(&mut self, exit: Exit, next: Label) {
+        // 不可达的空块只会生成一条多余的跳转
+        if self.dead && self.block.stmts.is_empty() && matches!(exit, Exit::Goto(..)) {
+            self.label = next;
+            self.dead = false;
+            return;
+        }
+        self.dead = false;
+        let Marked { stmts, marks } = std::mem::take(&mut self.block);
+        self.blocks.push(Block {
+            label: std::mem::replace(&mut self.label, next),
+            stmts,
+            marks,
+            exit,
+        });
+    }
+
+    /// 放置标签，之后的语句从标签处开始
+    pub fn place(&mut self, label: Label) {
+        self.close(Exit::Goto(label), label);
+    }
+
+    /// 无条件跳转，之后的语句在放置新的标签之前都不可达
+    pub fn goto(&mut self, target: Label) {
+        self.close(Exit::Goto(target), Label::alloc());
+        self.dead = true;
+    }
+
+    /// 条件成立时跳转到`then`，否则执行之后的语句
+    pub fn branch(&mut self, left: Variable, cond: JumpCondition, right: Variable, then: Label) {
+        let next = Label::alloc();
+        let exit = Exit::Branch {
+            left,
+            cond,
+            right,
+            then,
+            r#else: next,
+        };
+        self.close(exit, next);
+    }
+
+    /// 调用函数，返回后执行之后的语句
+    pub fn call(&mut self, define: &FnDefine) {
+        let next = Label::alloc();
+        let exit = Exit::Call {
+            id: define.id,
+            addr: Variable::new(
+                VariableName::named(&define.addr()),
+                VariableValue::UnknowType,
+            ),
+            entry: define.entry,
+            ret: next,
+        };
+        self.close(exit, next);
+    }
+
+    /// 跳回调用处，之后的语句在放置新的标签之前都不可达
+    pub fn r#return(&mut self, define: &FnDefine) {
+        self.push_stmt(Statement::set(
+            Variable::counter(),
+            Variable::new(
+                VariableName::named(&define.addr()),
+                VariableValue::UnknowType,
+            ),
+        ));
+        self.close(Exit::Indirect(Some(define.id)), Label::alloc());
+        self.dead = true;
     }
 
-    pub fn link<F>(&mut self, f: F) -> Result<(), Error>
-    where
-        F: FnOnce(&mut Self) -> Result<(), Error>,
-    {
-        let mut temp = Self::new();
-        f(&mut temp)?;
-        for mut stmt in temp.stmts {
-            if let Some(old_target) = stmt.jump_target() {
-                stmt.reset(self.stmts.len() + old_target);
+    /// 把`other`接在之后，之后的语句接着`other`的最后一个块
+    pub fn append(&mut self, other: Self) {
+        self.close(Exit::Goto(other.entry()), other.label);
+        self.blocks.extend(other.blocks);
+        self.block = other.block;
+        self.dead = other.dead;
+        self.fns.extend(other.fns);
+    }
+
+    /// 复制一份语句，用于展开函数和循环
+    ///
+    /// 其中的块都换成新的标签，跳转到其他地方的标签按照`targets`替换，暂存的函数体不会被复制
+    pub fn copy(&self, targets: &[(Label, Label)]) -> Self {
+        let mut labels: HashMap<Label, Label> = targets.iter().copied().collect();
+        for block in &self.blocks {
+            labels.insert(block.label, Label::alloc());
+        }
+        labels.insert(self.label, Label::alloc());
+
+        let mut blocks = self.blocks.clone();
+        for block in &mut blocks {
+            block.label = labels[&block.label];
+            for target in block.exit.targets_mut() {
+                *target = labels.get(target).copied().unwrap_or(*target);
             }
+        }
+        Self {
+            blocks,
+            label: labels[&self.label],
+            block: self.block.clone(),
+            dead: self.dead,
+            fns: vec![],
+        }
+    }
 
-            self.push_stmt(stmt);
+    /// 在调用处展开用的函数体，返回改为跳转到函数体之后
+    pub fn inlined(&self, id: usize) -> Self {
+        let mut body = self.copy(&[]);
+        let end = Label::alloc();
+        for block in &mut body.blocks {
+            if matches!(block.exit, Exit::Indirect(Some(ret)) if ret == id) {
+                // 移除`set @counter {函数名}.addr`
+                block.stmts.pop();
+                block.exit = Exit::Goto(end);
+            }
         }
-        Ok(())
+        body.place(end);
+        body
+    }
+
+    /// 暂存函数体，在`link_fns`时放到主程序之后
+    pub fn push_fn(&mut self, body: Statements) {
+        self.fns.push(body);
+    }
+
+    /// 把函数体放到主程序之后
+    ///
+    /// 主程序结束后会跳回开头，而不是落入函数体中
+    pub fn link_fns(&mut self) {
+        if self.fns.is_empty() {
+            return;
+        }
+        self.goto(self.entry());
+
+        // 函数体中也可能定义了函数
+        while !self.fns.is_empty() {
+            for body in std::mem::take(&mut self.fns) {
+                self.append(body);
+            }
+        }
+    }
+
+    /// 取出所有的块，应在`link_fns`之后调用
+    ///
+    /// 执行到最后一个块之后会回到第一个块
+    pub fn into_blocks(mut self) -> Vec<Block> {
+        self.close(Exit::Goto(self.entry()), Label::alloc());
+        self.blocks
+    }
+
+    /// 标记上一条语句
+    pub fn mark_last(&mut self, mark: Mark) {
+        self.block.mark_last(mark);
     }
 
     pub fn generate<C: CompileUnit>(
@@ -78,27 +279,39 @@ impl Statements {
     /// * 如果上一次运算是`op  symbol result v1 v2`,会返回result
     /// * 如果上一个运算是`set result = value     `,会直接移除上一行，然后返回value
     pub fn get_last_value(&mut self) -> Option<Variable> {
-        match self.stmts.pop().unwrap() {
+        match self.block.stmts.pop().unwrap() {
+            // 函数的返回值会被下一次调用覆盖，必须复制一份
+            Statement::Set { result, value } if value.value.is_fn_return() => {
+                let copy = result.clone();
+                self.push_stmt(Statement::set(result, value));
+                Some(copy)
+            }
             Statement::Set { result, value } => {
                 // 对于用分配的名字的变量，可以直接折叠
                 // 有名字的，也可以直接给值
-                return if result.name.is_index() || result.name.is_named() {
+                if result.name.is_index() || result.name.is_named() {
                     Some(value)
                 // 否则，复制其值
                 } else {
                     self.push_stmt(Statement::set(result, value.clone()));
                     Some(value)
-                };
+                }
             }
             // 对于二元表达式，只能复制result
             Statement::Operation { result, op, v1, v2 } => {
                 let value = result.clone();
                 self.push_stmt(Statement::Operation { result, op, v1, v2 });
-                return Some(value);
+                Some(value)
+            }
+            mut stmt => {
+                // 其他指令的结果同理，没有结果的指令值为null
+                let value = stmt
+                    .result_mut()
+                    .map_or_else(Variable::null, |result| result.clone());
+                self.push_stmt(stmt);
+                Some(value)
             }
-            stmt => self.push_stmt(stmt),
         }
-        None
     }
 
     /// 重命名上一次运算的结果,用于Bind
@@ -106,10 +319,39 @@ impl Statements {
     /// * 如果上一次运算是`op  symbol result v1 v2`,会重命名result
     /// * 如果上一次运算是`set result = value     `,会重命名result
     pub fn set_last_value<'a>(&mut self, new_name: &'a str) -> Result<&Variable, &'a str> {
-        match self.stmts.last_mut().ok_or(new_name)? {
+        // 没有结果的指令，比如没有返回值的内置函数，值为null
+        let no_result = self.block.stmts.last_mut().is_some_and(|last| {
+            !matches!(last, Statement::Set { .. } | Statement::Operation { .. })
+                && last.result_mut().is_none()
+        });
+        if no_result {
+            let result = Variable::new(VariableName::named(new_name), VariableValue::UnknowType);
+            self.push_stmt(Statement::set(result, Variable::null()));
+            let Some(Statement::Set { value, .. }) = self.block.stmts.last() else {
+                unreachable!()
+            };
+            return Ok(value);
+        }
+        let last = self.block.stmts.last_mut().ok_or(new_name)?;
+        // 其他指令的结果同理
+        if !matches!(last, Statement::Set { .. } | Statement::Operation { .. }) {
+            return match last.result_mut() {
+                Some(result) if !result.name.is_named() => {
+                    result.name = VariableName::named(new_name);
+                    Ok(result)
+                }
+                _ => Err(new_name),
+            };
+        }
+        match last {
             Statement::Set { result, value } if !result.name.is_named() => {
                 result.name = VariableName::named(new_name);
-                Ok(value)
+                // 函数返回值被复制之后，变量的值是未知的
+                if value.value.is_fn_return() {
+                    Ok(result)
+                } else {
+                    Ok(value)
+                }
             }
             Statement::Operation { result, .. } if !result.name.is_named() => {
                 result.name = VariableName::named(new_name);
@@ -119,44 +361,85 @@ impl Statements {
         }
     }
 
-    pub fn generate_jump(&mut self, target: usize, global: &mut GlobalSpace) -> Option<usize> {
-        use crate::abi::JumpCondition::{self, *};
-        match self.stmts.pop()? {
+    /// 把上一次运算的结果变成条件跳转，结果的真假为`when`时跳转到`target`
+    ///
+    /// 结果是常量时，要么无条件跳转，要么不跳转
+    pub fn generate_jump(&mut self, target: Label, when: bool) {
+        use crate::abi::JumpCondition::*;
+        let if_true = if when { Neq } else { Eq };
+        match self.block.stmts.pop().unwrap() {
             Statement::Set { result, value } => {
                 // 左值是分配的时，可以直接拿来用
-                if result.name.is_index() {
-                    self.push_stmt(Statement::jump(value, Neq, Variable::zero(), target));
+                let value = if result.name.is_index() {
+                    value
                 } else {
                     self.push_stmt(Statement::set(result.clone(), value));
-                    self.push_stmt(Statement::jump(result, Neq, Variable::zero(), target));
+                    result
+                };
+                match value.value.as_number() {
+                    Some(&number) if (number != 0.0) == when => self.goto(target),
+                    Some(_) => {}
+                    None => self.branch(value, if_true, Variable::zero(), target),
                 }
-                Some(self.stmts.len() - 1)
             }
             Statement::Operation { result, op, v1, v2 } => {
-                if result.name.is_index() && JumpCondition::try_from(op).is_ok() {
-                    self.push_stmt(Statement::jump(v1, op.try_into().unwrap(), v2, target))
-                } else {
-                    self.push_stmt(Statement::operation(result.clone(), op, v1, v2));
-                    self.push_stmt(Statement::jump(result, Neq, Variable::zero(), target));
+                // 比较运算可以直接变成跳转的条件，条件为假时跳转需要取反
+                let cond = if when { Some(op) } else { !op }
+                    .and_then(|op| JumpCondition::try_from(op).ok());
+                match cond {
+                    Some(cond) if result.name.is_index() => self.branch(v1, cond, v2, target),
+                    _ => {
+                        self.push_stmt(Statement::operation(result.clone(), op, v1, v2));
+                        self.branch(result, if_true, Variable::zero(), target);
+                    }
                 }
-                Some(self.stmts.len() - 1)
             }
             stmt => {
+                // 其他指令的结果，比如内置函数
                 self.push_stmt(stmt);
-                None
+                let value = self.get_last_value().unwrap();
+                self.branch(value, if_true, Variable::zero(), target);
             }
         }
     }
 
-    pub fn jump_always(&mut self, target: usize) -> usize {
-        use crate::abi::JumpCondition::Always;
-        self.push_stmt(Statement::jump(
-            Variable::zero(),
-            Always,
-            Variable::zero(),
-            target,
-        ));
-        self.stmts.len() - 1
+    /// 生成条件，条件的真假为`when`时跳转到`target`
+    ///
+    /// `&&`在左边为假时、`||`在左边为真时不会计算右边
+    pub fn generate_cond(
+        &mut self,
+        global: &mut GlobalSpace,
+        cond: &mut ast::Expr,
+        target: Label,
+        when: bool,
+    ) -> Result<(), Error> {
+        use crate::lexer::Symbol;
+        match cond {
+            ast::Expr::Op1 { op, rv } if op.symbol() == Symbol::Not => {
+                self.generate_cond(global, rv, target, !when)
+            }
+            ast::Expr::Op2 { lv, op, rv } if matches!(op.symbol(), Symbol::And | Symbol::Or) => {
+                // 左边为short时短路
+                let short = op.symbol() == Symbol::Or;
+                if short == when {
+                    // 短路的结果就是要跳转的结果
+                    self.generate_cond(global, lv, target, when)?;
+                    self.generate_cond(global, rv, target, when)
+                } else {
+                    // 短路时跳过右边
+                    let skip = Label::alloc();
+                    self.generate_cond(global, lv, skip, short)?;
+                    self.generate_cond(global, rv, target, when)?;
+                    self.place(skip);
+                    Ok(())
+                }
+            }
+            _ => {
+                self.generate(global, cond)?;
+                self.generate_jump(target, when);
+                Ok(())
+            }
+        }
     }
 }
 
@@ -164,15 +447,65 @@ pub trait CompileUnit: Debug + Display {
     fn generate(&mut self, global: &mut GlobalSpace, stmts: &mut Statements) -> Result<(), Error>;
 }
 
+/// 依次生成一串语句，同时检查不可达的代码
+///
+/// 如果其中某条语句之后的代码不可达，这串语句整体之后的代码同样不可达
+pub fn generate_compile_units(
+    cus: &mut [Box<dyn CompileUnit>],
+    global: &mut GlobalSpace,
+    stmts: &mut Statements,
+) -> Result<(), Error> {
+    let mut diverged = None;
+    let mut warned = false;
+    for cu in cus {
+        let len = stmts.len();
+        cu.generate(global, stmts)?;
+        // 空行之类不产生代码的语句不算
+        if let Some(location) = diverged {
+            if !warned && stmts.len() > len {
+                global.warn(WarnKind::UnreachableCode.make_warn(location));
+                warned = true;
+            }
+        }
+        if let Some(location) = global.take_diverged() {
+            diverged = diverged.or(Some(location));
+        }
+    }
+    if let Some(location) = diverged {
+        global.diverge(location);
+    }
+    Ok(())
+}
+
 impl CompileUnit for ast::Expr<'_> {
     fn generate(&mut self, global: &mut GlobalSpace, stmts: &mut Statements) -> Result<(), Error> {
         match self {
             // 对于直接的值，直接set给分配的名字
+            // `@`开头的是游戏内置的变量和属性
+            ast::Expr::Var { token, name } if name.starts_with('@') => {
+                // 各种内容的总数，比如`@itemCount`，不检查时拼错的名字会被当作值为null的内置变量
+                // sensor的属性，比如`@payloadCount`，不会作为值生成，所以不受影响
+                if name.ends_with("Count") {
+                    let counters: Vec<_> = LookUp::ALL.iter().map(LookUp::counter).collect();
+                    if !counters.iter().any(|counter| counter == name) {
+                        return Err(ErrorKind::NotOneOf(counters).make_error(token.location));
+                    }
+                }
+                stmts.push_stmt(Statement::set(
+                    Variable::alloc(),
+                    Variable::new(VariableName::named(name), VariableValue::MetaAttrib),
+                ));
+            }
             ast::Expr::Var { token, name } => {
-                let value = global.global_lookup_var(name, token.location)?;
+                // 变量的值在循环和分支中可能不同，交给生成之后的常量传播
+                global.global_lookup_var(name, token.location)?;
+                global.global_use_var(name, token.location);
                 stmts.push_stmt(Statement::set(
                     Variable::alloc(),
-                    Variable::new(VariableName::named(name), value.clone()),
+                    Variable::new(
+                        VariableName::named(&global.global_var_name(name)),
+                        VariableValue::UnknowType,
+                    ),
                 ));
             }
             // 对于直接的数字，直接set给分配的名字
@@ -180,6 +513,10 @@ impl CompileUnit for ast::Expr<'_> {
                 Variable::alloc(),
                 Variable::new(VariableName::index(), VariableValue::Number(*vul)),
             )),
+            ast::Expr::Color { vul, .. } => stmts.push_stmt(Statement::set(
+                Variable::alloc(),
+                Variable::new(VariableName::None, VariableValue::Color(*vul)),
+            )),
             // 对于直接的字符串，直接set给分配的名字
             ast::Expr::Str { vul, .. } => stmts.push_stmt(Statement::set(
                 Variable::alloc(),
@@ -204,7 +541,7 @@ impl CompileUnit for ast::Expr<'_> {
                         let stmt = if let Some(&number) = e.value.as_number() {
                             let value = Variable::new(
                                 VariableName::None,
-                                VariableValue::Number((number != 0.0) as u8 as f64),
+                                VariableValue::Number((number == 0.0) as u8 as f64),
                             );
                             Statement::set(Variable::alloc(), value)
                         } else {
@@ -241,11 +578,89 @@ impl CompileUnit for ast::Expr<'_> {
                         stmts.push_stmt(stmt);
                     }
                 },
-                // 按位取反行为暂时未知，搁置
-                crate::lexer::Symbol::Flip => todo!(),
+                // 按位取反 游戏中为 ~(long)x
+                crate::lexer::Symbol::Flip => {
+                    stmts.generate(global, &mut **rv)?;
+                    let e = stmts.get_last_value().unwrap();
+                    let stmt = if let Some(&number) = e.value.as_number() {
+                        let value = Variable::new(
+                            VariableName::None,
+                            VariableValue::Number(!(number as i64) as f64),
+                        );
+                        Statement::set(Variable::alloc(), value)
+                    } else {
+                        Statement::operation(Variable::alloc(), op.symbol(), e, Variable::zero())
+                    };
+                    stmts.push_stmt(stmt);
+                }
                 // 其他的不可能是
                 _ => panic!("unreachable"),
             },
+            /*
+                短路求值设计：
+                set t short
+                if l is short -> 'end
+                op notEqual t r 0
+                'end: set result t
+            */
+            ast::Expr::Op2 { lv, op, rv }
+                if matches!(
+                    op.symbol(),
+                    crate::lexer::Symbol::And | crate::lexer::Symbol::Or
+                ) =>
+            {
+                // `&&`在左边为假时短路，`||`在左边为真时短路
+                let short = op.symbol() == crate::lexer::Symbol::Or;
+                let to_bool = |value: Variable, result: Variable| match value.value.as_number() {
+                    Some(&number) => Statement::set(
+                        result,
+                        Variable::new(
+                            VariableName::None,
+                            VariableValue::Number((number != 0.0) as u8 as f64),
+                        ),
+                    ),
+                    None => Statement::operation(
+                        result,
+                        crate::lexer::Symbol::Neq,
+                        value,
+                        Variable::zero(),
+                    ),
+                };
+
+                stmts.generate(global, &mut **lv)?;
+                let l = stmts.get_last_value().unwrap();
+                match l.value.as_number() {
+                    // 左边是常量时，结果要么就是短路的结果，要么只取决于右边
+                    Some(&number) if (number != 0.0) == short => {
+                        stmts.push_stmt(to_bool(l, Variable::alloc()));
+                    }
+                    Some(_) => {
+                        stmts.generate(global, &mut **rv)?;
+                        let r = stmts.get_last_value().unwrap();
+                        stmts.push_stmt(to_bool(r, Variable::alloc()));
+                    }
+                    None => {
+                        let temp = Variable::alloc();
+                        let short_value = Variable::new(
+                            VariableName::None,
+                            VariableValue::Number(short as u8 as f64),
+                        );
+                        stmts.push_stmt(Statement::set(temp.clone(), short_value));
+                        let end = Label::alloc();
+                        let cond = if short {
+                            JumpCondition::Neq
+                        } else {
+                            JumpCondition::Eq
+                        };
+                        stmts.branch(l, cond, Variable::zero(), end);
+                        stmts.generate(global, &mut **rv)?;
+                        let r = stmts.get_last_value().unwrap();
+                        stmts.push_stmt(to_bool(r, temp.clone()));
+                        stmts.place(end);
+                        stmts.push_stmt(Statement::set(Variable::alloc(), temp));
+                    }
+                }
+            }
             // 如果是二元表达式
             ast::Expr::Op2 { lv, op, rv } => {
                 // 分别生成左右，进行取值
@@ -253,61 +668,76 @@ impl CompileUnit for ast::Expr<'_> {
                 let lv = stmts.get_last_value().unwrap();
                 stmts.generate(global, &mut **rv)?;
                 let rv = stmts.get_last_value().unwrap();
-                #[allow(unused)]
                 // 如果都是数字，可以直接折叠成set
-                let stmt = if let (Some(l), Some(r)) = (lv.value.as_number(), rv.value.as_number())
-                {
-                    // 常量折叠
-                    use std::ops::*;
-                    macro_rules! cast_bool {
-                        ($e : expr) => {
-                            $e as u8 as f64
-                        };
+                let folded = match (lv.value.as_number(), rv.value.as_number()) {
+                    (Some(&l), Some(&r)) => crate::abi::fold(op.symbol(), l, r),
+                    _ => None,
+                };
+                let stmt = match folded {
+                    Some(number) => {
+                        let value =
+                            Variable::new(VariableName::None, VariableValue::Number(number));
+                        Statement::set(Variable::alloc(), value)
                     }
-
-                    let number = match op.symbol() {
-                        crate::lexer::Symbol::Add => l.add(r),
-                        crate::lexer::Symbol::Sub => l.sub(r),
-                        crate::lexer::Symbol::Mul => l.mul(r),
-                        crate::lexer::Symbol::Div => l.div(r),
-                        crate::lexer::Symbol::IDiv => l.div_euclid(*r),
-                        crate::lexer::Symbol::Rem => l.rem(r),
-                        crate::lexer::Symbol::Pow => l.powf(*r),
-                        // 布尔运算 非零为true 零为false\
-                        // true 默认转化为 1.0
-                        crate::lexer::Symbol::Eq => cast_bool!(l.eq(r)),
-                        crate::lexer::Symbol::Neq => cast_bool!(l.ne(r)),
-                        crate::lexer::Symbol::And => cast_bool!(l.ne(&0.0) && l.ne(&0.0)),
-                        crate::lexer::Symbol::Or => cast_bool!(l.ne(&0.0) || l.ne(&0.0)),
-                        crate::lexer::Symbol::Lr => cast_bool!(l < r),
-                        crate::lexer::Symbol::Gr => cast_bool!(l > r),
-                        crate::lexer::Symbol::LrE => cast_bool!(l <= r),
-                        crate::lexer::Symbol::GrE => cast_bool!(l >= r),
-                        // Seq无法保证准确性：因为类型系统不完善
-                        crate::lexer::Symbol::Seq => cast_bool!(l.eq(r)),
-                        // Shl Shr Band Xor不确定实际行为
-                        crate::lexer::Symbol::Shl => ((*l as usize) << (*r as usize)) as f64,
-                        crate::lexer::Symbol::Shr => ((*l as usize) << (*r as usize)) as f64,
-                        crate::lexer::Symbol::Band => ((*l as usize) & (*r as usize)) as f64,
-                        crate::lexer::Symbol::Xor => ((*l as usize) ^ (*r as usize)) as f64,
-                        _ => todo!(),
-                    };
-                    let value = Variable::new(VariableName::None, VariableValue::Number(number));
-                    Statement::set(Variable::alloc(), value)
-                } else {
                     // 不然只能老老实实（
                     // 这个情况下，是无法进行常量折叠的，也就是说result会保留下来
-                    Statement::operation(Variable::alloc(), op.symbol(), lv, rv)
+                    None => Statement::operation(Variable::alloc(), op.symbol(), lv, rv),
                 };
                 stmts.push_stmt(stmt);
             }
+            ast::Expr::Sensor {
+                target,
+                attrib_token: _,
+                attrib,
+            } => {
+                let target = crate::intrinsic::building(global, stmts, target)?;
+                stmts.push_stmt(Statement::Sensor {
+                    result: Variable::alloc(),
+                    target,
+                    attrib: Variable::new(VariableName::named(attrib), VariableValue::MetaAttrib),
+                });
+            }
             ast::Expr::FnCall {
                 fn_name_token,
                 fn_name,
                 args,
             } => {
-                global.global_use_fn(fn_name, fn_name_token.location, args.len())?;
-                todo!("函数的代码合并，参数传递")
+                /*
+                    函数调用设计：
+                    calc arg1 .. argn
+                    set f.parm1 arg1
+                    ...
+                    set f.parmn argn
+                    op add f.addr @counter 1
+                    jump always -> 'f
+                    set result f.ret
+
+                    足够小的函数会直接展开函数体，而不是跳转
+                */
+                if let Some(intrinsic) = crate::intrinsic::lookup(fn_name) {
+                    return intrinsic.generate(global, stmts, fn_name_token.location, args);
+                }
+                let define = global.global_use_fn(fn_name, fn_name_token.location, args.len())?;
+                // 先计算全部参数，再进行赋值
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    stmts.generate(global, arg)?;
+                    values.push(stmts.get_last_value().unwrap());
+                }
+                for (parm, value) in define.parms.iter().zip(values) {
+                    stmts.push_stmt(Statement::set(
+                        Variable::new(VariableName::named(parm), VariableValue::UnknowType),
+                        value,
+                    ));
+                }
+                match global.inline_body(define.id) {
+                    Some(body) => stmts.append(body.copy(&[])),
+                    None => stmts.call(&define),
+                }
+                stmts.push_stmt(Statement::set(
+                    Variable::alloc(),
+                    Variable::new(VariableName::named(&define.ret()), VariableValue::FnReturn),
+                ));
             }
         }
         Ok(())
@@ -321,7 +751,7 @@ impl CompileUnit for ast::Bind<'_> {
                 var_tokens, vars, ..
             } => {
                 for i in 0..vars.len() {
-                    global.define_var(vars[i], var_tokens[i].location);
+                    global.global_define_var(vars[i], var_tokens[i].location);
                 }
             }
             ast::Bind::Init {
@@ -347,11 +777,9 @@ impl CompileUnit for ast::Bind<'_> {
                         };
 
                         expr.generate(global, stmts)?;
-                        let val = stmts.set_last_value(name).unwrap();
-                        global.define_var(name, token.location);
-                        global
-                            .global_ass_var(name, token.location, val.value.clone())
-                            .unwrap();
+                        global.global_define_var(name, token.location);
+                        stmts.set_last_value(&global.global_var_name(name)).unwrap();
+                        global.global_ass_var(name, token.location)?;
                     }
                 }
                 None => {
@@ -363,11 +791,9 @@ impl CompileUnit for ast::Bind<'_> {
                         let mut expr = vuls.pop().unwrap();
 
                         expr.generate(global, stmts)?;
-                        let val = stmts.set_last_value(name).unwrap();
-                        global.define_var(name, location);
-                        global
-                            .global_ass_var(name, location, val.value.clone())
-                            .unwrap();
+                        global.global_define_var(name, location);
+                        stmts.set_last_value(&global.global_var_name(name)).unwrap();
+                        global.global_ass_var(name, location)?;
                     }
                 }
             },
@@ -393,11 +819,9 @@ impl CompileUnit for ast::Bind<'_> {
                         };
 
                         expr.generate(global, stmts)?;
-                        let val = stmts.set_last_value(name).unwrap();
+                        stmts.set_last_value(&global.global_var_name(name)).unwrap();
                         // global.define_var(name, location);
-                        global
-                            .global_ass_var(name, token.location, val.value.clone())
-                            .unwrap();
+                        global.global_ass_var(name, token.location)?;
                     }
                 }
                 None => {
@@ -409,11 +833,9 @@ impl CompileUnit for ast::Bind<'_> {
                         let mut expr = vuls.pop().unwrap();
 
                         expr.generate(global, stmts)?;
-                        let val = stmts.set_last_value(name).unwrap();
+                        stmts.set_last_value(&global.global_var_name(name)).unwrap();
                         // global.define_var(name, location);
-                        global
-                            .global_ass_var(name, location, val.value.clone())
-                            .unwrap();
+                        global.global_ass_var(name, location)?;
                     }
                 }
             },
@@ -422,13 +844,10 @@ impl CompileUnit for ast::Bind<'_> {
     }
 }
 
-impl CompileUnit for ast::Block<'_> {
+impl CompileUnit for ast::Block {
     fn generate(&mut self, global: &mut GlobalSpace, stmts: &mut Statements) -> Result<(), Error> {
         global.new_space();
-        for stmt in &mut self.stmts {
-            // 防止跳转出现问题
-            stmts.link(|stmts| stmt.generate(global, stmts))?;
-        }
+        generate_compile_units(&mut self.stmts, global, stmts)?;
         global.close_space();
         Ok(())
     }
@@ -462,63 +881,44 @@ impl CompileUnit for ast::ControlFlow<'_> {
                 'end : ...
             */
             ast::ControlFlow::If {
+                r#if,
                 conditions,
                 blocks,
                 else_block,
                 ..
             } => {
-                let mut jumps = Vec::with_capacity(conditions.len());
-                for condition in conditions {
+                // 只有每个分支之后都不可达时，if之后才不可达
+                let mut all_diverged = else_block.is_some();
+                let labels: Vec<_> = blocks.iter().map(|_| Label::alloc()).collect();
+                let end = Label::alloc();
+                for (condition, &label) in conditions.iter_mut().zip(&labels) {
                     // calc cn
-                    stmts.generate(global, condition)?;
-                    // if cn -> '..
-                    let jump = stmts.generate_jump(0, global).unwrap();
-                    jumps.push(jump);
+                    // if cn -> 'bn
+                    stmts.generate_cond(global, condition, label, true)?;
                 }
 
-                let to_else_or_end = stmts.jump_always(0);
-                let mut to_ends = Vec::with_capacity(blocks.len());
-                for i in 0..blocks.len() {
-                    // 进行jump
-                    let jump = jumps[i];
-                    let stmts_len = stmts.stmts.len();
-                    stmts[jump].reset(stmts_len);
-
-                    // 链接，不然跳转会坏
-                    let block = &mut blocks[i];
-                    // stmts.generate(global, block)?;
-                    stmts.link(|stmts| stmts.generate(global, block).map(|_| ()))?;
-
-                    // 最后一个跳转块
-                    let to_end = if i == blocks.len() - 1 {
-                        if else_block.is_some() {
-                            // 有else块
-                            let to_end = stmts.jump_always(0);
-                            // 是to_else
-                            let stmts_len = stmts.stmts.len();
-                            stmts[to_else_or_end].reset(stmts_len);
-                            // 链接else块
-                            stmts.link(|stmts| {
-                                stmts
-                                    .generate(global, else_block.as_mut().unwrap())
-                                    .map(|_| ())
-                            })?;
-
-                            to_end
-                            //
-                        } else {
-                            // 没有else块 to_else_or_end -> to_end
-                            to_else_or_end
-                        }
-                    } else {
-                        stmts.jump_always(0)
-                    };
-                    to_ends.push(to_end);
+                // always -> 'else / end
+                let r#else = else_block.as_ref().map(|_| Label::alloc());
+                stmts.goto(r#else.unwrap_or(end));
+                for (block, label) in blocks.iter_mut().zip(labels) {
+                    // 'bn: ...
+                    stmts.place(label);
+                    stmts.generate(global, block)?;
+                    all_diverged &= global.take_diverged().is_some();
+                    // always -> end
+                    stmts.goto(end);
                 }
+                if let (Some(block), Some(r#else)) = (else_block, r#else) {
+                    // 'else : ...
+                    stmts.place(r#else);
+                    stmts.generate(global, block)?;
+                    all_diverged &= global.take_diverged().is_some();
+                }
+                // 'end : ...
+                stmts.place(end);
 
-                for to_end in to_ends {
-                    let stmts_len = stmts.stmts.len();
-                    stmts[to_end].reset(stmts_len);
+                if all_diverged {
+                    global.diverge(r#if.location);
                 }
             }
 
@@ -531,43 +931,650 @@ impl CompileUnit for ast::ControlFlow<'_> {
                 'end ..
             */
             ast::ControlFlow::While {
+                label,
                 r#while,
                 condition,
                 block,
             } => {
+                // 条件恒为真且没有break的循环永远不会结束
+                let forever = matches!(condition, ast::Expr::Num { vul, .. } if *vul != 0.0);
+                let (calc, end) = (Label::alloc(), Label::alloc());
                 // 'calc
-                let calc = stmts.stmts.len();
-                // 居然有Clone！！！好大的性能问题！！！
-                let mut condition = ast::Expr::Op2 {
-                    lv: Box::new(condition.clone()),
-                    op: ast::Op::Op {
-                        token: r#while,
-                        symbol: crate::lexer::Symbol::Eq,
-                    },
-                    rv: Box::new(ast::Expr::Num {
-                        token: r#while,
-                        vul: 0.0,
-                    }),
-                };
+                stmts.place(calc);
                 // calc cond
-                stmts.generate(global, &mut condition)?;
                 // if !cond -> 'end
-                let jump_to_end = stmts.generate_jump(0, global).unwrap();
+                stmts.generate_cond(global, condition, end, false)?;
                 // 'block
-                stmts.link(|stmts| stmts.generate(global, block).map(|_| ()))?;
+                global.enter_loop(label.map(|label| label.name), end, calc);
+                let result = stmts.generate(global, block);
+                let has_break = global.leave_loop();
+                result?;
+                // 循环体之后不可达，不代表循环之后不可达
+                global.take_diverged();
                 // jump always -> 'calc
-                stmts.jump_always(calc);
+                stmts.goto(calc);
                 // 'end
-                let len = stmts.stmts.len();
-                stmts[jump_to_end].reset(len);
+                stmts.place(end);
+
+                if forever && !has_break {
+                    global.diverge(r#while.location);
+                }
+            }
+
+            /*
+                loop跳转设计
+                'block: ..
+                jump always -> 'block
+                'end ..
+            */
+            ast::ControlFlow::Loop {
+                label,
+                r#loop,
+                block,
+            } => {
+                let (start, end) = (Label::alloc(), Label::alloc());
+                // 'block
+                stmts.place(start);
+                global.enter_loop(label.map(|label| label.name), end, start);
+                let result = stmts.generate(global, block);
+                let has_break = global.leave_loop();
+                result?;
+                global.take_diverged();
+                // jump always -> 'block
+                stmts.goto(start);
+                // 'end
+                stmts.place(end);
+
+                // 没有break的loop永远不会结束
+                if !has_break {
+                    global.diverge(r#loop.location);
+                }
+            }
+
+            /*
+                do while跳转设计
+                'block: ..
+                'calc: calc cond
+                if cond -> 'block
+                'end ..
+            */
+            ast::ControlFlow::DoWhile {
+                label,
+                r#do,
+                block,
+                condition,
+                ..
+            } => {
+                let forever = matches!(condition, ast::Expr::Num { vul, .. } if *vul != 0.0);
+                let (start, calc, end) = (Label::alloc(), Label::alloc(), Label::alloc());
+                // 'block
+                stmts.place(start);
+                global.enter_loop(label.map(|label| label.name), end, calc);
+                let result = stmts.generate(global, block);
+                let has_break = global.leave_loop();
+                result?;
+                // 循环体之后不可达时，条件也不可达，但continue仍然可能跳到条件
+                global.take_diverged();
+                // 'calc
+                stmts.place(calc);
+                // calc cond
+                // if cond -> 'block
+                stmts.generate_cond(global, condition, start, true)?;
+                // 'end
+                stmts.place(end);
+
+                if forever && !has_break {
+                    global.diverge(r#do.location);
+                }
+            }
+
+            /*
+                for跳转设计
+                set i start
+                set end_ end
+                set step_ step
+                'calc: if i >= end_ -> 'end  (步长为负数时是 <=，不是常量时在运行时判断)
+                'block: ..
+                'next: op add i i step
+                jump always -> 'calc
+                'end ..
+
+                -O2时，次数已知且不大的循环会展开为
+                set i start
+                block..
+                set i start + step
+                block..
+            */
+            ast::ControlFlow::For {
+                label,
+                var_token,
+                var,
+                start,
+                end,
+                step,
+                block,
+            } => {
+                // 循环变量只在循环内可见
+                global.new_space();
+
+                // set i start
+                stmts.generate(global, start)?;
+                global.global_define_var(var, var_token.location);
+                stmts.set_last_value(&global.global_var_name(var)).unwrap();
+                global.global_ass_var(var, var_token.location)?;
+
+                // break跳到'end，continue跳到'next
+                let (r#break, next_round) = (Label::alloc(), Label::alloc());
+                let unroll = match global.level().unroll_limit() {
+                    0 => None,
+                    limit => for_values(start, end, step.as_ref(), limit),
+                };
+                // 展开时需要先知道循环体的长度
+                let mut body = None;
+                if let Some(values) = unroll {
+                    global.enter_loop(label.map(|label| label.name), r#break, next_round);
+                    let mut block_stmts = Statements::new();
+                    let result = block_stmts.generate(global, block).map(|_| ());
+                    global.leave_loop();
+                    global.take_diverged();
+                    result?;
+
+                    let len = block_stmts.len();
+                    if values.len() * (len + 1) <= global.level().unroll_limit() {
+                        // 循环变量不一定在循环体中使用
+                        global.global_use_var(var, var_token.location);
+                        stmts.fns.append(&mut block_stmts.fns);
+                        let name = global.global_var_name(var);
+                        for (round, value) in values.into_iter().enumerate() {
+                            // 第一轮的值已经在上面设置了
+                            if round != 0 {
+                                stmts.push_stmt(Statement::set(
+                                    Variable::new(
+                                        VariableName::named(&name),
+                                        VariableValue::UnknowType,
+                                    ),
+                                    Variable::new(VariableName::None, VariableValue::Number(value)),
+                                ));
+                            }
+                            // 每一轮的continue跳到下一轮的开头
+                            let next = Label::alloc();
+                            stmts.append(block_stmts.copy(&[(next_round, next)]));
+                            stmts.place(next);
+                        }
+                        stmts.place(r#break);
+                        global.close_space();
+                        return Ok(());
+                    }
+                    body = Some(block_stmts);
+                }
+
+                // end和step只在进入循环前计算一次，循环体中修改它们不影响循环的次数
+                let mut once = |expr: &mut ast::Expr| -> Result<Variable, Error> {
+                    stmts.generate(global, expr)?;
+                    let value = stmts.get_last_value().unwrap();
+                    if value.value.is_number() || value.name.is_index() {
+                        return Ok(value);
+                    }
+                    let temp = Variable::alloc();
+                    stmts.push_stmt(Statement::set(temp.clone(), value));
+                    Ok(temp)
+                };
+                let end = once(end)?;
+                let step = match step {
+                    Some(step) => once(step)?,
+                    None => Variable::new(VariableName::None, VariableValue::Number(1.0)),
+                };
+                global.global_use_var(var, var_token.location);
+                let i = Variable::new(
+                    VariableName::named(&global.global_var_name(var)),
+                    VariableValue::UnknowType,
+                );
+
+                // 'calc
+                use crate::abi::JumpCondition::{GrE, Lr, LrE};
+                let calc = Label::alloc();
+                stmts.place(calc);
+                match step.value.as_number() {
+                    Some(&step) => {
+                        let cond = if step < 0.0 { LrE } else { GrE };
+                        stmts.branch(i.clone(), cond, end, r#break);
+                    }
+                    // 步长不是常量时，在运行时根据它的符号选择条件
+                    None => {
+                        let (desc, start) = (Label::alloc(), Label::alloc());
+                        stmts.branch(step.clone(), Lr, Variable::zero(), desc);
+                        stmts.branch(i.clone(), GrE, end.clone(), r#break);
+                        stmts.goto(start);
+                        stmts.place(desc);
+                        stmts.branch(i.clone(), LrE, end, r#break);
+                        stmts.place(start);
+                    }
+                }
+                // 'block
+                match body {
+                    Some(body) => stmts.append(body),
+                    None => {
+                        global.enter_loop(label.map(|label| label.name), r#break, next_round);
+                        let result = stmts.generate(global, block).map(|_| ());
+                        global.leave_loop();
+                        global.take_diverged();
+                        result?;
+                    }
+                }
+
+                // 'next: op add i i step
+                stmts.place(next_round);
+                stmts.push_stmt(Statement::operation(
+                    i.clone(),
+                    crate::lexer::Symbol::Add,
+                    i,
+                    step,
+                ));
+                // jump always -> 'calc
+                stmts.goto(calc);
+                // 'end
+                stmts.place(r#break);
+
+                global.close_space();
             }
         }
         Ok(())
     }
 }
 
+/// 次数已知的for循环中循环变量的每一个值，不是字面量或者超过`limit`次时返回None
+fn for_values(
+    start: &ast::Expr,
+    end: &ast::Expr,
+    step: Option<&ast::Expr>,
+    limit: usize,
+) -> Option<Vec<f64>> {
+    let literal = |expr: &ast::Expr| match expr {
+        ast::Expr::Num { vul, .. } => Some(*vul),
+        ast::Expr::Op1 { op, rv } if op.symbol() == crate::lexer::Symbol::Sub => match **rv {
+            ast::Expr::Num { vul, .. } => Some(-vul),
+            _ => None,
+        },
+        _ => None,
+    };
+    let (mut i, end) = (literal(start)?, literal(end)?);
+    let step = step.map_or(Some(1.0), literal)?;
+
+    let mut values = vec![];
+    while if step < 0.0 { i > end } else { i < end } {
+        // 步长为0时也会在这里停下
+        if values.len() == limit {
+            return None;
+        }
+        values.push(i);
+        i += step;
+    }
+    Some(values)
+}
+
 impl CompileUnit for ast::FnDef<'_> {
+    /// 函数体不会出现在主程序中，而是暂存起来，等`link_fns`时放到最后
+    ///
+    /// 参数、局部变量和返回地址都是`{函数名}.{名字}`形式的全局变量，所以递归调用会在调用处报错
+    fn generate(&mut self, global: &mut GlobalSpace, stmts: &mut Statements) -> Result<(), Error> {
+        if crate::intrinsic::lookup(self.fn_name).is_some() {
+            return Err(ErrorKind::RedefineIntrinsic(self.fn_name.to_owned())
+                .make_error(self.fn_name_token.location));
+        }
+        let define =
+            global.global_define_fn(self.fn_name, self.fn_name_token.location, &self.parms)?;
+
+        global.new_space();
+        for i in 0..self.parms.len() {
+            global.define_parm(
+                self.parms[i],
+                &define.parms[i],
+                self.parm_tokens[i].location,
+            );
+        }
+        let outer = global.enter_fn(define.clone());
+        let mut body = Statements::with_entry(define.entry);
+        let result = self.block.generate(global, &mut body);
+        global.leave_fn(outer);
+        global.close_space();
+        let diverged = global.take_diverged();
+        result?;
+
+        // 隐式的返回，函数体的最后已经返回时就不需要了
+        if diverged.is_none() {
+            return_null(&mut body, &define);
+            body.r#return(&define);
+        }
+        // 展开时，返回变成直接执行之后的语句
+        let inlined = body.inlined(define.id);
+        if global.level().inline(inlined.len()) {
+            global.define_inline(define.id, inlined);
+        }
+        stmts.push_fn(body);
+        Ok(())
+    }
+}
+
+/// 没有返回值时返回null，否则调用处会读到上一次调用的返回值
+fn return_null(stmts: &mut Statements, define: &FnDefine) {
+    stmts.push_stmt(Statement::set(
+        Variable::new(
+            VariableName::named(&define.ret()),
+            VariableValue::UnknowType,
+        ),
+        Variable::null(),
+    ));
+}
+
+impl CompileUnit for ast::Return<'_> {
     fn generate(&mut self, global: &mut GlobalSpace, stmts: &mut Statements) -> Result<(), Error> {
+        let define = global
+            .this_fn()
+            .cloned()
+            .ok_or_else(|| ErrorKind::ReturnOutsideFn.make_error(self.r#return.location))?;
+
+        match &mut self.vul {
+            Some(vul) => {
+                stmts.generate(global, vul)?;
+                stmts.set_last_value(&define.ret()).unwrap();
+            }
+            None => return_null(stmts, &define),
+        }
+        stmts.r#return(&define);
+        global.diverge(self.r#return.location);
+        Ok(())
+    }
+}
+
+impl CompileUnit for ast::LoopJump<'_> {
+    /// 跳转到所属循环的开头或者结尾
+    fn generate(&mut self, global: &mut GlobalSpace, stmts: &mut Statements) -> Result<(), Error> {
+        let keyword = if self.r#break { "break" } else { "continue" };
+        let target = match self.label {
+            Some(label) => global
+                .find_loop(Some(label.name), self.r#break)
+                .ok_or_else(|| {
+                    ErrorKind::UnDefinedLabel(label.name.to_owned())
+                        .make_error(label.token.location)
+                })?,
+            None => global.find_loop(None, self.r#break).ok_or_else(|| {
+                ErrorKind::JumpOutsideLoop(keyword.to_owned()).make_error(self.token.location)
+            })?,
+        };
+
+        stmts.goto(target);
+        global.diverge(self.token.location);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        opt::{Level, PassManager},
+        testing::{generate, mlog},
+    };
+
+    fn error(src: &str) -> Error {
+        generate(src, Level::O0).unwrap_err()
+    }
+
+    #[test]
+    fn call() {
+        let dist = "fn dist(x, y) {\n    return x * x + y * y\n}\n";
+        let src = format!("{dist}let a\nlet d = dist(a, 2) * dist(3, a)\n");
+        let cfg = generate(&src, Level::O0).unwrap();
+        let calls = cfg
+            .blocks()
+            .iter()
+            .filter(|block| matches!(block.exit, Exit::Call { .. }))
+            .count();
+        assert_eq!(calls, 2);
+        assert_eq!(
+            crate::mlog::emit(&cfg.lower()),
+            "set dist.x a\n\
+             set dist.y 2\n\
+             op add dist.addr @counter 1\n\
+             jump 12 always 0 0\n\
+             set __t0 dist.ret\n\
+             set dist.x 3\n\
+             set dist.y a\n\
+             op add dist.addr @counter 1\n\
+             jump 12 always 0 0\n\
+             set __t1 dist.ret\n\
+             op mul d __t0 __t1\n\
+             jump 0 always 0 0\n\
+             op mul __t2 dist.x dist.x\n\
+             op mul __t3 dist.y dist.y\n\
+             op add dist.ret __t2 __t3\n\
+             set @counter dist.addr\n"
+        );
+
+        assert!(matches!(
+            error(&format!("{dist}dist(1)\n")).kind,
+            ErrorKind::CallFnWithIncorrectArgs(_, 2, 1)
+        ));
+    }
+
+    #[test]
+    fn r#return() {
+        let src = "fn abs(x) {\n    if x < 0 {\n        return -x\n    }\n    return x\n}\nlet a\nlet b = abs(a) * 2\n";
+        assert_eq!(
+            mlog(src),
+            "set abs.x a\n\
+             op add abs.addr @counter 1\n\
+             jump 6 always 0 0\n\
+             set __t0 abs.ret\n\
+             op mul b __t0 2\n\
+             jump 0 always 0 0\n\
+             jump 8 lessThan abs.x 0\n\
+             jump 10 always 0 0\n\
+             op sub abs.ret 0 abs.x\n\
+             set @counter abs.addr\n\
+             set abs.ret abs.x\n\
+             set @counter abs.addr\n"
+        );
+
+        assert!(matches!(
+            error("return 1\n").kind,
+            ErrorKind::ReturnOutsideFn
+        ));
+
+        // 提前返回会跳到`f.addr`，递归调用会在内层覆盖它
+        let src = "fn f(x) {\n    if x {\n        return f(f(x - 1))\n    }\n    return 0\n}\n";
+        let e = error(src);
+        assert!(matches!(e.kind, ErrorKind::RecursiveCall(..)));
+        assert_eq!(e.location.unwrap().line, 2);
+
+        // 在函数外嵌套调用时，内层调用已经返回，`f.addr`才会被外层调用覆盖
+        let src =
+            "fn g(x) {\n    if x {\n        return 1\n    }\n    return 0\n}\nlet y = g(g(2))\n";
+        let mlog = mlog(src);
+        let calls: Vec<_> = mlog
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| *line == "op add g.addr @counter 1")
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(calls.len(), 2);
+        // 外层调用的参数是内层调用的返回值，在内层调用之后才会保存返回地址
+        assert!(mlog
+            .lines()
+            .nth(calls[1] - 1)
+            .unwrap()
+            .starts_with("set g.x __t"));
+    }
+
+    #[test]
+    fn locals() {
+        // 函数中的变量以函数名为前缀，不会覆盖同名的全局变量
+        let src = "fn f(a) {\n    let c = a * 2\n    for i in 0..c {}\n    return c\n}\nlet c = 5\nlet d = f(1)\nprint(c, d)\n";
+        let mlog = mlog(src);
+        assert!(mlog.contains("op mul f.c f.a 2"), "{mlog}");
+        assert!(mlog.contains("set f.i 0"), "{mlog}");
+        assert!(mlog.contains("set c 5\n"), "{mlog}");
+        assert!(mlog.contains("print c\n"), "{mlog}");
+
+        let passes = PassManager::new(Level::O2);
+        let program = crate::testing::compile(src, Level::O2, &passes);
+        let mlog = crate::mlog::emit(&program);
+        assert!(mlog.ends_with("print 5\nprint 2\n"), "{mlog}");
+    }
+
+    #[test]
+    fn return_null() {
+        // 没有返回值的返回和隐式的返回都把返回值设为null
+        let src = "fn f(a) {\n    if a {\n        return\n    }\n    print(a)\n}\nlet x = f(1)\n";
+        let mlog = mlog(src);
+        assert_eq!(mlog.matches("set f.ret null\n").count(), 2, "{mlog}");
+    }
+
+    #[test]
+    fn recursion() {
+        let src = "fn fact(n) {\n    if n <= 1 {\n        return 1\n    }\n    return n * fact(n - 1)\n}\n";
+        let e = error(src);
+        assert!(matches!(&e.kind, ErrorKind::RecursiveCall(name) if name == "fact"));
+        assert_eq!(e.location.unwrap().line, 4);
+
+        // 内层函数调用了正在定义的外层函数，在外层函数调用内层函数时成环
+        let src = "fn f(n) {\n    fn g(m) {\n        return f(m)\n    }\n    return g(n)\n}\n";
+        let e = error(src);
+        assert!(matches!(&e.kind, ErrorKind::RecursiveCall(name) if name == "g"));
+        assert_eq!(e.location.unwrap().line, 4);
+    }
+
+    #[test]
+    fn flip() {
+        let src = "let a\nlet b = ~a\nlet c = ~5 + (-1 << 65)\n";
+        assert_eq!(
+            mlog(src),
+            "op not b a 0\n\
+             set c -8\n"
+        );
+    }
+
+    #[test]
+    fn for_loop() {
+        let src = "let n\nfor i in 0..n step 2 {\n    print(i)\n}\nfor j in 5..0 step -1 {}\n";
+        assert_eq!(
+            mlog(src),
+            "set i 0\n\
+             set __t0 n\n\
+             jump 6 greaterThanEq i __t0\n\
+             print i\n\
+             op add i i 2\n\
+             jump 2 always 0 0\n\
+             set j 5\n\
+             jump 10 lessThanEq j 0\n\
+             op add j j -1\n\
+             jump 7 always 0 0\n"
+        );
+
+        // 步长不是常量时在运行时判断方向，end和step都只计算一次
+        let src = "let n, s\nfor i in 0..n * 2 step s {\n    n += 1\n}\n";
+        assert_eq!(
+            mlog(src),
+            "set i 0\n\
+             op mul __t0 n 2\n\
+             set __t1 s\n\
+             jump 6 lessThan __t1 0\n\
+             jump 10 greaterThanEq i __t0\n\
+             jump 7 always 0 0\n\
+             jump 10 lessThanEq i __t0\n\
+             op add n n 1\n\
+             op add i i __t1\n\
+             jump 3 always 0 0\n"
+        );
+
+        // 循环变量只在循环内可见
+        let src = "for k in 0..3 {}\nprint(k)\n";
+        assert!(generate(src, Level::O0).is_err());
+    }
+
+    #[test]
+    fn short_circuit() {
+        let src = "let a, b\nlet x = a || b\nlet y = 1 && 0 || !3\nif a && !(b || a) {\n    print(x)\n}\n";
+        assert_eq!(
+            mlog(src),
+            "set __t0 1\n\
+             jump 3 notEqual a 0\n\
+             op notEqual __t0 b 0\n\
+             set x __t0\n\
+             set y 0\n\
+             jump 8 equal a 0\n\
+             jump 8 notEqual b 0\n\
+             jump 9 equal a 0\n\
+             jump 10 always 0 0\n\
+             print x\n"
+        );
+    }
+
+    #[test]
+    fn loop_and_do_while() {
+        let src = "let x\nloop {\n    do {\n        x += 1\n        continue\n    } while x < 10\n    break\n}\n";
+        assert_eq!(
+            mlog(src),
+            "op add x x 1\n\
+             jump 0 lessThan x 10\n"
+        );
+    }
+
+    #[test]
+    fn loop_jump() {
+        let src = "let x\n'outer: for i in 0..3 {\n    while x {\n        if x > i {\n            continue 'outer\n        }\n        break\n    }\n}\n";
+        assert_eq!(
+            mlog(src),
+            "set i 0\n\
+             jump 8 greaterThanEq i 3\n\
+             jump 6 equal x 0\n\
+             jump 5 greaterThan x i\n\
+             jump 6 always 0 0\n\
+             jump 6 always 0 0\n\
+             op add i i 1\n\
+             jump 1 always 0 0\n"
+        );
+
+        let error = |src| error(src).kind;
+        assert!(matches!(error("break\n"), ErrorKind::JumpOutsideLoop(..)));
+        // 函数体中看不到外层的循环
+        let src = "while 1 {\n    fn f() {\n        continue\n    }\n}\n";
+        assert!(matches!(error(src), ErrorKind::JumpOutsideLoop(..)));
+        let src = "while 1 {\n    break 'a\n}\n";
+        assert!(matches!(error(src), ErrorKind::UnDefinedLabel(..)));
+    }
+
+    #[test]
+    fn draw_buffer() {
+        let draws = "draw(rect, 0, 0, 1, 1)\n".repeat(DRAW_BUFFER);
+        let check = |src: &str| {
+            crate::testing::compile(src, Level::O0, &PassManager::new(Level::O0))
+                .check_draw_buffer()
+        };
+
+        // 循环的次数未知，不会警告
+        let src = "let i\nwhile i < 300 {\n    draw(col, 0)\n    i += 1\n}\n";
+        assert!(check(src).is_empty());
+
+        let src =
+            format!("let x\nif x {{\n    draw(clear, 0, 0, 0)\n}}\n{draws}drawflush(display1)\n");
+        let warns = check(&src);
+        assert_eq!(warns.len(), 1);
+        assert_eq!(
+            warns[0].location.unwrap().read_location(),
+            (DRAW_BUFFER + 3, 0)
+        );
+
+        let src = format!("{draws}drawflush(display1)\n{draws}");
+        assert!(check(&src).is_empty());
+
+        // 函数中的draw计入调用之后的部分，多次调用只警告一次
+        let half = "    draw(rect, 0, 0, 1, 1)\n".repeat(DRAW_BUFFER / 2);
+        let src = format!("fn f() {{\n{half}}}\nf()\nf()\nf()\ndrawflush(display1)\n");
+        let warns = check(&src);
+        assert_eq!(warns.len(), 1);
+        assert_eq!(warns[0].location.unwrap().read_location(), (1, 4));
+        let src = format!("fn f() {{\n{half}}}\nf()\ndrawflush(display1)\nf()\n");
+        assert!(check(&src).is_empty());
+    }
+}
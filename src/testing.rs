@@ -0,0 +1,45 @@
+//! 测试中共用的编译流程
+
+use crate::{
+    cfg::{Cfg, Program},
+    error::Error,
+    lexer::Token,
+    meta::GlobalSpace,
+    opt::{Level, PassManager},
+    syn::{CompileUnit, Statements},
+};
+
+/// 词法分析的结果
+pub fn tokens(src: &str) -> &'static [Token] {
+    // 解析结果借用了tokens，测试中直接泄漏，避免悬垂引用
+    Box::leak(crate::lexer::Lexer::new(src).toekns().into_boxed_slice())
+}
+
+/// 解析源码，源码中不能有语法错误
+pub fn parse(src: &str) -> Vec<Box<dyn CompileUnit>> {
+    let (cus, errors) = crate::parser::Parser::new(tokens(src)).get_compile_units();
+    assert!(errors.is_empty(), "{errors:?}");
+    cus
+}
+
+/// 按照`level`生成代码，函数体已经放到主程序之后
+pub fn generate(src: &str, level: Level) -> Result<Cfg, Error> {
+    let mut global = GlobalSpace::new();
+    global.set_level(level);
+    let mut stmts = Statements::new();
+    crate::syn::generate_compile_units(&mut parse(src), &mut global, &mut stmts)?;
+    stmts.link_fns();
+    Ok(Cfg::new(stmts))
+}
+
+/// 生成代码并经过`passes`优化，源码中不能有错误
+pub fn compile(src: &str, level: Level, passes: &PassManager) -> Program {
+    let mut cfg = generate(src, level).unwrap();
+    passes.run(&mut cfg);
+    cfg.lower()
+}
+
+/// 不展开函数也不优化时的mlog
+pub fn mlog(src: &str) -> String {
+    crate::mlog::emit(&compile(src, Level::O0, &PassManager::new(Level::O0)))
+}